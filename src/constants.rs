@@ -12,3 +12,12 @@ pub const LUT_WIDTH: usize = 8;
 
 // Height of the LUT (in pixels)
 pub const LUT_HEIGHT: usize = 16;
+
+// Lighting channels of lights and surfaces without `pbr_channels` / `pbr_receive_channels` (channel 0)
+pub const DEFAULT_LIGHT_CHANNELS: u32 = 1;
+
+// Texture lights use the side id above this as their light id, so they never collide with entity ids
+pub const TEXLIGHT_ID_BASE: u64 = 1 << 32;
+
+// Blockers stored per light in the LUT (2 rows each, starting at row 4)
+pub const LUT_MAX_BLOCKERS: usize = 2;
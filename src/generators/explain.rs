@@ -0,0 +1,91 @@
+use std::fmt::Write as _;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::scoring::{LightExplanation, ScoreReason};
+use crate::types::LightCluster;
+
+#[derive(Serialize)]
+struct ClusterReport<'a> {
+    cluster: &'a str,
+    surface: &'a str,
+    surface_id: u64,
+    center: [f32; 3],
+    min_score: f32,
+    selected: usize,
+    lights: &'a [LightExplanation],
+}
+
+/// Writes the `--explain` report for clusters with explanations, as `<path>.json` and `<path>.md`.
+/// Returns the number of explained clusters
+pub fn generate(path: &Path, clusters: &[LightCluster]) -> anyhow::Result<usize> {
+    let reports: Vec<ClusterReport> = clusters.iter()
+        .filter(|cluster| !cluster.explanations.is_empty())
+        .map(|cluster| ClusterReport {
+            cluster: &cluster.name,
+            surface: &cluster.ggx_surface_name,
+            surface_id: cluster.ggx_surface_id,
+            center: [cluster.bound.center[0], cluster.bound.center[1], cluster.bound.center[2]],
+            min_score: cluster.min_cluster_score,
+            selected: cluster.lights.len(),
+            lights: &cluster.explanations,
+        })
+        .collect();
+
+    if reports.is_empty() {
+        return Ok(0);
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path.with_extension("json"), serde_json::to_string_pretty(&reports)?)?;
+    std::fs::write(path.with_extension("md"), markdown(&reports))?;
+
+    Ok(reports.len())
+}
+
+fn markdown(reports: &[ClusterReport]) -> String {
+    let mut out = String::from("# Light selection report\n");
+
+    for report in reports {
+        let _ = writeln!(out, "\n## {} (surface '{}', id {})\n", report.cluster, report.surface, report.surface_id);
+        let _ = writeln!(out, "Center: {:.0} {:.0} {:.0}, min_score: {:.2}, selected: {}\n",
+            report.center[0], report.center[1], report.center[2], report.min_score, report.selected);
+
+        // Far away lights only clutter the table
+        let (far, lights): (Vec<_>, Vec<_>) = report.lights.iter()
+            .partition(|light| light.breakdown.reason == ScoreReason::DistanceCull);
+
+        out.push_str("| Light | Type | Verdict | Slot | Score | Raw score | Distance | Attenuation | Window | Visible |\n");
+        out.push_str("|---|---|---|---|---|---|---|---|---|---|\n");
+        for light in lights {
+            let b = &light.breakdown;
+            let name = match (&light.target_name, &light.proxy) {
+                (_, Some(proxy)) => format!("{} -> {}", light.light, proxy),
+                (target, None) if !target.is_empty() => format!("{} ({})", light.light, target),
+                _ => light.light.clone(),
+            };
+            let _ = writeln!(out, "| {} | {} | {:?} | {} | {:.3} | {:.3} | {:.0} | {:.3} | {:.3} | {}/{} |",
+                name,
+                light.light_type,
+                b.reason,
+                light.slot.map(|s| s.to_string()).unwrap_or_default(),
+                light.normalized_score,
+                b.score,
+                b.distance,
+                b.attenuation,
+                b.window,
+                b.visible_samples,
+                b.total_samples,
+            );
+        }
+
+        if !far.is_empty() {
+            let _ = writeln!(out, "\n{} more lights are out of range.", far.len());
+        }
+    }
+
+    out
+}
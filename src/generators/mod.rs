@@ -1,3 +1,4 @@
+pub mod explain;
 pub mod vtf_lut;
 pub mod vmt_patch;
 pub mod vscript;
@@ -3,7 +3,7 @@ use std::io::Write;
 use crate::vmt_helper::VmtPbrParams;
 
 /// Generates a Patch VMT that includes the base PBR shader and inserts the generated LUT
-pub fn generate(vmt_path: &Path, texture_rel_path: &str, params: &VmtPbrParams, initial_c4: &[f32; 4], cubemap_path: Option<&str>) -> anyhow::Result<()> {
+pub fn generate(vmt_path: &Path, texture_rel_path: &str, params: &VmtPbrParams, initial_c4: &[f32; 4], cubemap_path: Option<&str>, blend_cubemap_path: Option<&str>) -> anyhow::Result<()> {
     if let Some(parent) = vmt_path.parent() {
         std::fs::create_dir_all(parent)?;
     }
@@ -27,6 +27,10 @@ pub fn generate(vmt_path: &Path, texture_rel_path: &str, params: &VmtPbrParams,
         writeln!(file, "\t\t$texture2 \"{}\"", env_map)?;
     } else if let Some(cpath) = cubemap_path {
         writeln!(file, "\t\t$texture2 \"{}\"", cpath)?;
+        // Second cubemap for blending between volumes, its box and weight are in the LUT
+        if let Some(bpath) = blend_cubemap_path {
+            writeln!(file, "\t\t$texture4 \"{}\"", bpath)?;
+        }
     }
 
     writeln!(file, "\t\t$texture3 \"{}\"", params.mrao_map)?;
@@ -197,6 +197,10 @@ fn generate_meta(light: &LightDef) -> String {
         LightType::Point => "Point".to_string(),
         LightType::Spot { .. } => "Spot".to_string(),
         LightType::Rect { width, height, .. } => format!("Rect | Size: {}x{}", width, height),
+        LightType::Directional { .. } => "Directional".to_string(),
+        LightType::Sphere { radius } => format!("Sphere | Radius: {}", radius),
+        LightType::Tube { length, radius, .. } => format!("Tube | Size: {}x{}", length, radius),
+        LightType::Disk { radius, .. } => format!("Disk | Radius: {}", radius),
     };
 
     // Note: 'Shadow' status is not explicitly stored in LightDef in current parser,
@@ -1,10 +1,10 @@
 use std::path::Path;
 
 use crate::math::Vec3;
-use crate::types::LightType;
+use crate::types::{BlockerType, LightType};
 use crate::{types::LightCluster, vmt_helper::VmtPbrParams};
 
-use crate::constants::{LUT_WIDTH, LUT_HEIGHT};
+use crate::constants::{LUT_MAX_BLOCKERS, LUT_WIDTH, LUT_HEIGHT};
 
 pub fn generate(cluster: &LightCluster, output_path: &Path, params: &VmtPbrParams) -> anyhow::Result<()> {
     let num_lights = cluster.lights.len();
@@ -26,6 +26,7 @@ pub fn generate(cluster: &LightCluster, output_path: &Path, params: &VmtPbrParam
 
     for (i, (light, _score)) in cluster.lights.iter().take(LUT_WIDTH).enumerate() {
         let mut dir = Vec3::ZERO;
+        let mut right_axis = Vec3::ZERO;
         let mut param1 = 0.0;
         let mut param2 = 0.0;
         let mut extra_param = 0.0;
@@ -47,18 +48,39 @@ pub fn generate(cluster: &LightCluster, output_path: &Path, params: &VmtPbrParam
             }
             LightType::Rect {
                 direction,
+                right,
                 width: w,
                 height: h,
                 bidirectional,
             } => {
                 type_id = 2.0;
                 dir = *direction;
+                right_axis = *right;
                 param1 = *w;
                 param2 = *h;
                 if *bidirectional {
                     extra_param = 1.0;
                 }
             }
+            LightType::Directional { direction } => {
+                type_id = 3.0;
+                dir = *direction;
+            }
+            LightType::Sphere { radius } => {
+                type_id = 4.0;
+                param1 = *radius;
+            }
+            LightType::Tube { direction, length, radius } => {
+                type_id = 5.0;
+                dir = *direction;
+                param1 = *length;
+                param2 = *radius;
+            }
+            LightType::Disk { direction, radius } => {
+                type_id = 6.0;
+                dir = *direction;
+                param1 = *radius;
+            }
         }
 
         // WRITE TO TEXTURE ROWS
@@ -71,39 +93,45 @@ pub fn generate(cluster: &LightCluster, output_path: &Path, params: &VmtPbrParam
             rgba_pixels[row * LUT_WIDTH + i] = (0.0, 0.0, 0.0, 0.0);
         }
 
-        for (b_idx, b) in light.blockers.iter()
-            .enumerate()
-            .filter_map(|(i, opt)| opt.as_ref().map(|val| (i, val)))
-        {
-            let base_row = 4 + (b_idx * 2);
-            let is_fizzler = b.flag == 2;
+        // Row 9: light orientation. Rect width axis, the height axis is `right x dir`
+        rgba_pixels[9 * LUT_WIDTH + i] = (right_axis[0], right_axis[1], right_axis[2], 0.0);
 
-            // Blocker Params: Size
-            if is_fizzler {
-                rgba_pixels[base_row * LUT_WIDTH + i] = (b.width, b.depth, b.height, b.flag as f32);
-            } else {
-                rgba_pixels[base_row * LUT_WIDTH + i] = (b.width, b.height, b.depth, b.flag as f32);
-            }
+        // Rows 4-7: blockers, 2 rows each. Rows 10-11: blocker rotations. Rows 12-13: blocker tints
+        for (b_idx, b) in light.blockers.iter().take(LUT_MAX_BLOCKERS).enumerate() {
+            let base_row = 4 + (b_idx * 2);
 
-            // Blocker Offset
-            let blocker_world_pos = b.pos.unwrap_or(light.pos);
-            let diff = blocker_world_pos - light.pos;
+            // Fizzlers keep the legacy layout the shader reads for them: world AABB size as (x, z, y)
+            // and the offset in light space. No rotation, no tint
+            if b.kind == BlockerType::Fizzler {
+                let aabb = b.oriented_box().bounds();
+                let size = aabb.max - aabb.min;
+                rgba_pixels[base_row * LUT_WIDTH + i] = (size[0], size[2], size[1], b.kind.flag());
 
-            if is_fizzler {
-                // Project offset to light local space for Fizzlers
                 let light_dir = dir.normalize();
                 let up_base = if light_dir[2].abs() > 0.99 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
                 let right = light_dir.cross(up_base).normalize();
                 let up = right.cross(light_dir).normalize();
+                let diff = aabb.center - light.pos;
+                rgba_pixels[(base_row + 1) * LUT_WIDTH + i] = (diff.dot(right), diff.dot(up), diff.dot(light_dir), 0.0);
 
-                let off_x = diff.dot(right);
-                let off_y = diff.dot(up);
-                let off_z = diff.dot(light_dir);
-                rgba_pixels[(base_row + 1) * LUT_WIDTH + i] = (off_x, off_y, off_z, 0.0);
-            } else {
-                // World space offset
-                rgba_pixels[(base_row + 1) * LUT_WIDTH + i] = (diff[0], diff[1], diff[2], 0.0);
+                rgba_pixels[(10 + b_idx) * LUT_WIDTH + i] = (0.0, 0.0, 0.0, 1.0);
+                rgba_pixels[(12 + b_idx) * LUT_WIDTH + i] = (1.0, 1.0, 1.0, 1.0);
+                continue;
             }
+
+            // Blocker Params: Size along the box local axes + type flag
+            rgba_pixels[base_row * LUT_WIDTH + i] = (b.width, b.height, b.depth, b.kind.flag());
+
+            // Blocker Offset, world space
+            let diff = b.pos - light.pos;
+            rgba_pixels[(base_row + 1) * LUT_WIDTH + i] = (diff[0], diff[1], diff[2], 0.0);
+
+            // Blocker Rotation, box local -> world quaternion
+            let q = b.oriented_box().rotation_quat();
+            rgba_pixels[(10 + b_idx) * LUT_WIDTH + i] = (q[0], q[1], q[2], q[3]);
+
+            // Blocker Tint (tinted glass only, white otherwise)
+            rgba_pixels[(12 + b_idx) * LUT_WIDTH + i] = (b.tint[0], b.tint[1], b.tint[2], 1.0);
         }
     }
 
@@ -143,15 +171,44 @@ pub fn generate(cluster: &LightCluster, output_path: &Path, params: &VmtPbrParam
         params.metalness_scale,
     );
 
+    // --- WRITE AMBIENT RESIDUAL (ROW 14) ---
+    // L1 SH of the rejected lights, one RGB coefficient per pixel (L0, L1y, L1z, L1x). Pixel 0 alpha = has ambient
+    let has_ambient = if cluster.ambient_sh[0] != Vec3::ZERO { 1.0 } else { 0.0 };
+    for (i, coeff) in cluster.ambient_sh.iter().enumerate() {
+        let flag = if i == 0 { has_ambient } else { 0.0 };
+        rgba_pixels[14 * LUT_WIDTH + i] = (coeff[0], coeff[1], coeff[2], flag);
+    }
+
     // --- WRITE PCC DATA (ROW 15) ---
     if let Some(pcc) = &cluster.pcc_volume {
         let row = 15;
-        // World Space Box Min
-        rgba_pixels[row * LUT_WIDTH + 0] = (pcc.ws_min[0], pcc.ws_min[1], pcc.ws_min[2], 1.0);
+        // World Space Box Min. Alpha = box projection, 0 for a plain env_cubemap
+        let parallax = if pcc.parallax { 1.0 } else { 0.0 };
+        rgba_pixels[row * LUT_WIDTH + 0] = (pcc.ws_min[0], pcc.ws_min[1], pcc.ws_min[2], parallax);
         // World Space Box Max
         rgba_pixels[row * LUT_WIDTH + 1] = (pcc.ws_max[0], pcc.ws_max[1], pcc.ws_max[2], 1.0);
         // Pixel 2: Cubemap Origin
         rgba_pixels[row * LUT_WIDTH + 2] = (pcc.cubemap_pos[0], pcc.cubemap_pos[1], pcc.cubemap_pos[2], 1.0);
+
+        // Pixels 3-5: World -> Volume transform (3x4 rows), the volume becomes a [-1, 1] box. Handles rotated volumes
+        if pcc.parallax {
+            for (i, r) in pcc.volume.world_to_unit_box().iter().enumerate() {
+                rgba_pixels[row * LUT_WIDTH + 3 + i] = (r[0], r[1], r[2], r[3]);
+            }
+        }
+
+        // Blended cubemap, same layout split over the free pixels: row 14 pixels 4-7, row 15 pixels 6-7.
+        // Cubemap origin alpha = blend factor. A template `$envmap` leaves no room for the second cubemap, see vmt_patch
+        if let Some((blend, factor)) = cluster.pcc_blend.as_ref().filter(|_| params.env_map.is_none()) {
+            rgba_pixels[14 * LUT_WIDTH + 4] = (blend.ws_min[0], blend.ws_min[1], blend.ws_min[2], 1.0);
+            rgba_pixels[14 * LUT_WIDTH + 5] = (blend.ws_max[0], blend.ws_max[1], blend.ws_max[2], 1.0);
+            rgba_pixels[14 * LUT_WIDTH + 6] = (blend.cubemap_pos[0], blend.cubemap_pos[1], blend.cubemap_pos[2], *factor);
+
+            let slots = [14 * LUT_WIDTH + 7, row * LUT_WIDTH + 6, row * LUT_WIDTH + 7];
+            for (slot, r) in slots.into_iter().zip(blend.volume.world_to_unit_box()) {
+                rgba_pixels[slot] = (r[0], r[1], r[2], r[3]);
+            }
+        }
     }
 
     // -----------------------------------------------------
@@ -11,5 +11,5 @@ pub mod types;
 pub use constants::*;
 pub use types::*;
 pub use processing::surface_wrappers::{GgxSurfaceEnt, GgxSolid};
-pub use processing::{cubemaps, dynamic, geometry, scoring, surface_wrappers, tracer};
-pub use generators::{vmt_patch, vtf_lut, vscript};
+pub use processing::{cubemaps, dynamic, geometry, instances, light_rules, manifest, proxies, scoring, surface_wrappers, tracer, visgroups};
+pub use generators::{explain, vmt_patch, vtf_lut, vscript};
@@ -1,8 +1,9 @@
-use VMF_to_PBR::{vmt_helper::VmtPbrParams, *};
+use VMF_to_PBR::{rad_helper::RadLights, vmt_helper::VmtPbrParams, *};
 
 use anyhow::Context;
 use clap::Parser;
 use log::{debug, error, info, warn};
+use rayon::prelude::*;
 use simplelog::{LevelFilter, SimpleLogger};
 use source_fs::{DummyVpk, FileSystem, FileSystemOptions, P2GameInfo};
 use std::{collections::HashMap, path::PathBuf};
@@ -12,7 +13,7 @@ use vmf_forge::prelude::{Entity, VmfFile};
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// Input VMF file
+    /// Input VMF file or Hammer manifest (.vmm)
     #[arg(short, long)]
     input: PathBuf,
 
@@ -48,17 +49,66 @@ struct Args {
     /// Dump cluster scoring data to the console for debugging
     #[arg(long, default_value_t = false)]
     dump_clusters: bool,
+
+    /// Write a report on why lights were picked or dropped for this surface (wildcards allowed). Can be repeated.
+    ///  Saved next to the input map as '{map}_explain.json' / '.md', also with --draft-run
+    #[arg(long, value_name = "SURFACE")]
+    explain: Vec<String>,
+
+    /// Drop lights that don't fit into the LUT instead of merging them into proxy lights
+    #[arg(long, default_value_t = false)]
+    no_proxy_lights: bool,
+
+    /// Don't merge clusters with identical lights into shared materials
+    #[arg(long, default_value_t = false)]
+    no_cluster_merge: bool,
+
+    /// Don't generate parallax volumes for env_cubemaps outside of every func_parallax_volume
+    #[arg(long, default_value_t = false)]
+    no_auto_volumes: bool,
+
+    /// Save generated parallax volumes into the output VMF as func_parallax_volume brushes
+    #[arg(long, default_value_t = false)]
+    save_auto_volumes: bool,
+
+    /// Don't turn emissive '.rad' textures (lights.rad / <map>.rad) into area lights
+    #[arg(long, default_value_t = false)]
+    no_texlights: bool,
+
+    /// Bake this visgroup even if it's hidden in Hammer (wildcards allowed). Can be repeated
+    #[arg(long, value_name = "NAME")]
+    include_visgroup: Vec<String>,
+
+    /// Skip this visgroup even if it's visible in Hammer (wildcards allowed). Can be repeated
+    #[arg(long, value_name = "NAME")]
+    exclude_visgroup: Vec<String>,
+
+    /// How lights are rated per surface: 'legacy' (falloff and visibility) or 'brdf' (material response)
+    #[arg(long, value_enum, default_value_t = scoring::ScoringMode::Legacy)]
+    scoring: scoring::ScoringMode,
+
+    /// Number of worker threads. Defaults to the number of logical CPUs
+    #[arg(short, long)]
+    jobs: Option<usize>,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     setup_logging(args.verbose)?;
 
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .context("Failed to set up the thread pool")?;
+    }
+
     if !args.input.exists() {
         error!("Input file does not exist: {:?}", args.input);
         return Ok(());
     }
 
+    let is_manifest = args.input.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vmm"));
     let vmf_output = match args.output_vmf {
         Some(p) => p,
         None => {
@@ -67,7 +117,7 @@ fn main() -> anyhow::Result<()> {
                 let new_stem = format!("{}_pbr", stem.to_string_lossy());
                 p.set_file_name(new_stem);
             }
-            p.set_extension("vmf");
+            p.set_extension(if is_manifest { "vmm" } else { "vmf" });
             p
         }
     };
@@ -91,48 +141,121 @@ fn main() -> anyhow::Result<()> {
     let vfs = FileSystem::<DummyVpk>::load_from_path::<P2GameInfo>(&game_dir, &options)
         .context("Failed to load filesystem. Check if gameinfo.txt exists")?;
 
-    // Parse VMF
-    let mut file = std::fs::File::open(&args.input)?;
-    let mut vmf = VmfFile::parse_file(&mut file)?;
+    // Parse VMF (or all submaps of a manifest). Instances are collapsed, so everything inside them is visible
+    // to the baker. Hidden objects stay in the VMF, but lights, surfaces and the collision world skip them.
+    // Visgroup overrides are written into the VMF too, so the saved map compiles the baked variant
+    let visgroup_filter = visgroups::VisgroupFilter::new(args.include_visgroup, args.exclude_visgroup);
+    let mut manifest = None;
+    let (mut vmf, visible) = if is_manifest {
+        let mut m = manifest::Manifest::open(&args.input)?;
+        m.apply_visgroup_overrides(&visgroup_filter);
+        m.expand_instances(&game_dir, &visgroup_filter);
+        let merged = m.merge();
+        let visible = m.visible_copy(&merged, &visgroup_filter);
+        manifest = Some(m);
+        (merged, visible)
+    } else {
+        let mut file = std::fs::File::open(&args.input)?;
+        let mut vmf = VmfFile::parse_file(&mut file)?;
+        visgroups::apply_overrides(&mut vmf, &visgroup_filter);
+        instances::expand_instances(&mut vmf, &args.input, &game_dir, &visgroup_filter);
+        let visible = visgroups::visible_copy(&vmf, &visgroup_filter);
+        (vmf, visible)
+    };
 
     // Extract Lights
-    let all_lights = vmf_parser::extract_lights(&vmf)?;
-    let world_brushes = geometry::build_collision_world(&vmf);
-    let pcc_volumes = cubemaps::process_cubemaps(&vmf);
+    let mut all_lights = vmf_parser::extract_lights(&visible)?;
+    if !args.no_texlights {
+        let rad_lights = RadLights::load(&vfs, &args.input.with_extension("rad"));
+        all_lights.extend(vmf_parser::extract_texture_lights(&visible, &rad_lights));
+    }
+    let world_brushes = geometry::build_collision_world(&visible);
+    let mut cubemaps = cubemaps::process_cubemaps(&visible);
+    let auto_volumes = if args.no_auto_volumes { Vec::new() } else { cubemaps.generate_volumes(&world_brushes) };
     let light_connection_registry = dynamic::build_connections_registry(&vmf); // todo: maybe move to LIGHT struct?
 
     info!("Found {} PBR lights total", all_lights.len());
     info!("Registry built. Tracked targets: {}", light_connection_registry.len());
-    info!("Found {} PCC volumes.", pcc_volumes.len());
+    info!("Found {} PCC volumes ({} generated).", cubemaps.volumes.len(), auto_volumes.len());
 
     // == Step 1: Generate STUFF ==
-    let (ggx_ents, retained_ents): (Vec<_>, Vec<_>) = vmf.entities
-        .drain(..)
-        .partition(|ent| {
-            ent.classname().unwrap_or("").to_lowercase() == "func_ggx_surface"
-        });
+    // Ids for new solids and sides, taken before the surfaces leave the VMF
+    let mut next_id = instances::max_id(&vmf) + 1;
+    let (ggx_ents, retained_ents) = GgxSurfaceEnt::partition_visible(std::mem::take(&mut vmf.entities.0), &visible);
+    drop(visible);
 
     vmf.entities.0 = retained_ents;
 
-    let mut ggx_surfaces: Vec<GgxSurfaceEnt> = ggx_ents
-        .into_iter() // todo: use rayon!
-        .map(GgxSurfaceEnt::new)
-        .collect();
+    let mut ggx_surfaces: Vec<GgxSurfaceEnt> = GgxSurfaceEnt::from_entities(ggx_ents, &vmf.entities);
 
+    // Parse every template VMT once. BRDF scoring needs them before clustering, asset generation after
+    let mut materials_cache: HashMap<String, VmtPbrParams> = HashMap::new();
+    for ggx_surface in ggx_surfaces.iter_mut() {
+        let vmt = materials_cache.entry(ggx_surface.template_material.clone()).or_insert_with(|| {
+            debug!("Parsing VMT for material: {}", ggx_surface.template_material);
+            match VmtPbrParams::find_and_parse(&vfs, &ggx_surface.template_material) {
+                Ok(vmt) => vmt,
+                Err(m) => {
+                    error!("Failed to process VMT: {} ({}). Skipping...", ggx_surface.template_material, m);
+                    VmtPbrParams::default()
+                }
+            }
+        });
+
+        ggx_surface.scoring_mode = args.scoring;
+        ggx_surface.proxy_lights = !args.no_proxy_lights;
+        ggx_surface.explain = args.explain.iter().any(|pattern| text::wildcard_match(pattern, &ggx_surface.name));
+        ggx_surface.material = scoring::SurfaceMaterial::from_vmt(vmt, ggx_surface.override_roughness_mult);
+    }
+
+    // Surfaces with `pbr_auto_split` get their solids cut where different parts need different lights
+    ggx_surfaces.par_iter_mut().for_each(|ggx_surface| ggx_surface.auto_split(&all_lights, &world_brushes));
+    ggx_surfaces.iter().for_each(|ggx_surface| ggx_surface.renumber_split_solids(&mut next_id));
+
+    // Parallel collect keeps the surface order, so the cluster order is the same for any thread count
     let mut clusters: Vec<LightCluster> = ggx_surfaces
-        .iter_mut() // todo: use rayon!
-        .flat_map(|ggx_surface| {
+        .par_iter()
+        .flat_map_iter(|ggx_surface| {
             LightCluster::from_ggx_surface(
-                ggx_surface, &ggx_surface.name, &map_name, &game_dir, &all_lights, &world_brushes, &pcc_volumes
+                ggx_surface, &ggx_surface.name, &map_name, &game_dir, &all_lights, &world_brushes, &cubemaps
             )
         })
         .collect();
 
+    if !cubemaps.volumes.is_empty() {
+        let mut outside: Vec<&str> = clusters.iter()
+            .filter(|c| c.pcc_volume.as_ref().is_none_or(|pcc| !pcc.parallax || pcc.fallback))
+            .map(|c| c.ggx_surface_name.as_str())
+            .collect();
+        outside.dedup();
+        if !outside.is_empty() {
+            warn!("{} surfaces are not inside any func_parallax_volume, a plain env_cubemap or the nearest volume is used: {}", outside.len(), outside.join(", "));
+        }
+    }
+
     // optional filtering and skipping of clusters with no lights
     if args.drop_unlit {
         clusters.retain(|c| !c.lights.is_empty());
     }
 
+    // Explained before merging, which keeps only one cluster (and its explanations) of each group
+    if !args.explain.is_empty() {
+        let report_path = args.input.with_file_name(format!("{}_explain", map_name));
+        match explain::generate(&report_path, &clusters)? {
+            0 => warn!("No surfaces matched --explain {:?}", args.explain),
+            n => info!("Wrote light selection report for {} clusters to {:?}", n, report_path.with_extension("md")),
+        }
+    }
+
+    // Clusters that would bake the same LUT share one material
+    if !args.no_cluster_merge {
+        let before = clusters.len();
+        clusters = LightCluster::merge_identical(clusters);
+        if clusters.len() < before {
+            info!("Merged {} clusters with identical lights", before - clusters.len());
+        }
+    }
+
     dumping_generated_data(args.dump_lights, args.dump_clusters, &all_lights, &clusters);
     info!("Generated {} LUT clusters", clusters.len());
 
@@ -143,37 +266,26 @@ fn main() -> anyhow::Result<()> {
     }
 
     // GENERATE ASSETS
-    let mut materials_cache: HashMap<&str, VmtPbrParams> = HashMap::new();
-    for cluster in &mut clusters {
+    // Write LUTs and patch VMTs in parallel
+    clusters.par_iter().try_for_each(|cluster| -> anyhow::Result<()> {
         let vtf_lut_name = format!("maps/{}/{}", map_name, cluster.surface_material);
         let vtf_path = cluster.surface_material_path.with_extension("vtf");
         let vmt_path = cluster.surface_material_path.with_extension("vmt");
+        let orig_vmt = &materials_cache[cluster.pbr_material.as_str()];
 
-        let orig_vmt = materials_cache.entry(&cluster.pbr_material).or_insert_with(|| {
-            debug!("Parsing VMT for material: {}", cluster.pbr_material);
-            match VmtPbrParams::find_and_parse(&vfs, &cluster.pbr_material) {
-                Ok(vmt) => vmt,
-                Err(m) => {
-                    error!("Failed to process VMT: {} ({}). Skipping...", cluster.pbr_material, m);
-                    VmtPbrParams::default()
-                }
-            }
-        });
-
-        // dbg!(&orig_vmt);
-
-        if let Err(e) = vtf_lut::generate(&cluster, &vtf_path, &orig_vmt) {
+        if let Err(e) = vtf_lut::generate(cluster, &vtf_path, orig_vmt) {
             error!("Failed to create VTF for {:?}: {}", cluster.name, e);
         }
 
         vmt_patch::generate(
             &vmt_path,
             &vtf_lut_name,
-            &orig_vmt,
+            orig_vmt,
             &cluster.initial_c4,
             cluster.cubemap_name.as_deref(),
-        )?;
-    }
+            cluster.blend_cubemap_name.as_deref(),
+        )
+    })?;
 
     // Generate VScript Data
     let nut_path = game_dir
@@ -203,10 +315,31 @@ fn main() -> anyhow::Result<()> {
     let pbr_surface_entities: Vec<Entity> = ggx_surfaces.into_iter().map(|s| s.convert_to_illusionary()).collect();
     vmf.entities.extend(pbr_surface_entities);
 
+    if args.save_auto_volumes && !auto_volumes.is_empty() {
+        for obb in &auto_volumes {
+            let ent = cubemaps::volume_entity(obb, &mut next_id);
+            // In a manifest, the volume goes to the submap of its env_cubemap
+            if let Some(manifest) = manifest.as_mut()
+                && let Some(cubemap) = vmf.entities.iter().find(|e| {
+                    e.classname() == Some("env_cubemap") && e.get("origin").is_some_and(|o| obb.contains(math::Vec3::parse(o)))
+                })
+            {
+                manifest.place_with(ent.id(), cubemap.id());
+            }
+            vmf.entities.push(ent);
+        }
+        info!("Saved {} generated parallax volumes into the VMF", auto_volumes.len());
+    }
+
     // remove fake PBR entities
     vmf_parser::strip_pbr_entities(&mut vmf);
-    vmf.save(&vmf_output)?; // and saving!
-    info!("Saved modified VMF to: {:?}", vmf_output);
+    if let Some(mut manifest) = manifest {
+        manifest.save_split(vmf, &vmf_output)?;
+        info!("Saved modified manifest to: {:?}", vmf_output);
+    } else {
+        vmf.save(&vmf_output)?; // and saving!
+        info!("Saved modified VMF to: {:?}", vmf_output);
+    }
 
     Ok(())
 }
@@ -0,0 +1,125 @@
+use crate::math::{AABB, Vec3};
+use super::tracer::ray_aabb_intersect;
+
+// Max primitives stored in a single leaf
+const LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+struct BvhNode {
+    bounds: AABB,
+    // Leaf: index of the first primitive in `indices`. Inner: index of the right child (left is always `self + 1`)
+    offset: usize,
+    // Number of primitives in a leaf, 0 for inner nodes
+    count: usize,
+}
+
+/// Bounding Volume Hierarchy over a list of AABBs.
+/// Stores only indices, so the primitives themselves live wherever the caller keeps them.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+    prim_bounds: Vec<AABB>,
+}
+
+impl Bvh {
+    /// Builds the tree using a median split along the longest axis of the centroid bounds
+    pub fn build(bounds: &[AABB]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len().max(1) * 2 / LEAF_SIZE + 1),
+            indices: (0..bounds.len()).collect(),
+            prim_bounds: bounds.to_vec(),
+        };
+
+        if !bounds.is_empty() {
+            bvh.build_node(0, bounds.len());
+        }
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn build_node(&mut self, start: usize, end: usize) -> usize {
+        let node_idx = self.nodes.len();
+        let mut node_bounds = AABB::new();
+        let mut centroid_bounds = AABB::new();
+        for &i in &self.indices[start..end] {
+            let b = &self.prim_bounds[i];
+            node_bounds.extend(b.min);
+            node_bounds.extend(b.max);
+            centroid_bounds.extend(b.center);
+        }
+
+        self.nodes.push(BvhNode { bounds: node_bounds, offset: start, count: end - start });
+        if end - start <= LEAF_SIZE {
+            return node_idx;
+        }
+
+        // Longest axis of centroids
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent[0] >= extent[1] && extent[0] >= extent[2] { 0 }
+            else if extent[1] >= extent[2] { 1 }
+            else { 2 };
+
+        // All centroids at one spot, can't split it any further
+        if extent[axis] <= 0.0 {
+            return node_idx;
+        }
+
+        let mid = start + (end - start) / 2;
+        let prim_bounds = &self.prim_bounds;
+        self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+            prim_bounds[a].center[axis].total_cmp(&prim_bounds[b].center[axis])
+        });
+
+        self.build_node(start, mid);
+        let right = self.build_node(mid, end);
+
+        let node = &mut self.nodes[node_idx];
+        node.offset = right;
+        node.count = 0;
+        node_idx
+    }
+
+    /// Calls `visit` for every primitive whose AABB is crossed by the ray segment `[0, max_dist]`.
+    /// Returning `false` from `visit` stops the traversal early.
+    pub fn traverse_ray(&self, origin: Vec3, dir: Vec3, max_dist: f32, mut visit: impl FnMut(usize) -> bool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if !ray_aabb_intersect(origin, dir, max_dist, &node.bounds) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &prim in &self.indices[node.offset..node.offset + node.count] {
+                    if ray_aabb_intersect(origin, dir, max_dist, &self.prim_bounds[prim]) && !visit(prim) {
+                        return;
+                    }
+                }
+            } else {
+                stack.push(node.offset);
+                stack.push(node_idx + 1);
+            }
+        }
+    }
+
+    /// Collects all primitives crossed by the ray, sorted by their original index
+    pub fn query_ray(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Vec<usize> {
+        let mut hits = Vec::new();
+        self.traverse_ray(origin, dir, max_dist, |idx| {
+            hits.push(idx);
+            true
+        });
+        hits.sort_unstable();
+        hits
+    }
+}
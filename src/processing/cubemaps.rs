@@ -1,97 +1,514 @@
 use log::{info, warn};
-use vmf_forge::prelude::VmfFile;
-use crate::math::Vec3;
-use super::geometry;
+use vmf_forge::prelude::{Entity, VmfFile};
+use crate::math::{self, AABB, OrientedBox, Vec3};
+use super::geometry::{self, CollisionWorld};
+use super::tracer;
 use crate::types::ParallaxCubemap;
 
 
-pub fn find_parallax_volume(origin: Vec3, surface_normal: Vec3, pcc_volumes: &[ParallaxVolume]) -> Option<ParallaxCubemap> {
-    let mut best_pcc_data = None;
-
-    // todo: made better way to find closest cubemap
-    for vol in pcc_volumes {
-        // if origin[0] >= vol.ws_min[0] && origin[0] <= vol.ws_max[0] &&
-        //    origin[1] >= vol.ws_min[1] && origin[1] <= vol.ws_max[1] &&
-        //    origin[2] >= vol.ws_min[2] && origin[2] <= vol.ws_max[2]
-        // {
-                if !vol.cubemaps_origins.is_empty() {
-                    let mut best_score = f32::MIN;
-                    let mut best_c = vol.cubemaps_origins[0];
-
-                    for &c in &vol.cubemaps_origins {
-                        let to_cubemap = c - origin;
-                        let dist_sq = to_cubemap.dot(to_cubemap);
-                        let mut score = -dist_sq;
-
-                        let dir_to_cubemap = to_cubemap.normalize();
-                        let facing = surface_normal.dot(dir_to_cubemap);
-
-                        // Cubemap behind ur back? GFY!
-                        if facing < 0.0 {
-                            score -= 1_000_000.0;
-                        } else {
-                            score += facing * 100.0;
-                        }
-
-                        if score > best_score {
-                            best_score = score;
-                            best_c = c;
-                        }
-                    }
-
-                    best_pcc_data = Some(crate::types::ParallaxCubemap {
-                        cubemap_pos: best_c,
-                        ws_min: vol.ws_min,
-                        ws_max: vol.ws_max,
-                    });
+// Coverage difference below which two volumes count as a tie
+const COVERAGE_TIE: f32 = 0.01;
+// Surfaces this close to another volume blend in its cubemap
+const BLEND_MARGIN: f32 = 32.0;
+// Smaller blend factors aren't worth a second cubemap
+const MIN_BLEND: f32 = 0.05;
+// A room traced from a cubemap is open if a ray finds no wall this close
+const AUTO_VOLUME_MAX_DIST: f32 = 8192.0;
+
+/// Cubemap of a surface, and the one to blend in. In order of preference: a parallax volume covering the surface,
+/// a visible env_cubemap, the nearest volume (`fallback`), any env_cubemap
+pub fn select_cubemap(side_ids: &[u32], bound: &AABB, surface_normal: Vec3, cubemaps: &Cubemaps, world: &CollisionWorld) -> Option<(ParallaxCubemap, Option<(ParallaxCubemap, f32)>)> {
+    find_parallax_volume(bound, surface_normal, &cubemaps.volumes)
+        .or_else(|| find_env_cubemap(side_ids, bound, surface_normal, &cubemaps.env_cubemaps, world, true).map(|pcc| (pcc, None)))
+        .or_else(|| nearest_parallax_volume(bound, surface_normal, &cubemaps.volumes).map(|pcc| (pcc, None)))
+        .or_else(|| find_env_cubemap(side_ids, bound, surface_normal, &cubemaps.env_cubemaps, world, false).map(|pcc| (pcc, None)))
+}
+
+/// Picks the parallax volume for a surface: the one covering most of `bound`, ties broken by the best cubemap
+/// (close and in front of the surface). None if no volume touches the surface.
+/// Surfaces at the border of another volume also get its cubemap, with the blend factor of it (up to 0.5)
+pub fn find_parallax_volume(bound: &AABB, surface_normal: Vec3, pcc_volumes: &[ParallaxVolume]) -> Option<(ParallaxCubemap, Option<(ParallaxCubemap, f32)>)> {
+    let origin = bound.center;
+    let candidates = pcc_volumes.iter()
+        .filter(|vol| !vol.cubemaps_origins.is_empty())
+        .map(|vol| {
+            let (cubemap, score) = best_cubemap(vol, origin, surface_normal);
+            (vol, coverage(bound, vol), cubemap, score)
+        });
+
+    let best = candidates.clone()
+        .filter(|(_, coverage, _, _)| *coverage > 0.0)
+        .max_by(|a, b| {
+            if (a.1 - b.1).abs() > COVERAGE_TIE {
+                a.1.total_cmp(&b.1)
+            } else {
+                a.3.total_cmp(&b.3)
+            }
+        });
+
+    let (vol, _, cubemap, _) = best?;
+
+    // Coverage of the grown bound, so surfaces right at a doorway blend too
+    let mut grown = *bound;
+    grown.extend(bound.min - Vec3::ONE * BLEND_MARGIN);
+    grown.extend(bound.max + Vec3::ONE * BLEND_MARGIN);
+    let own_coverage = coverage(&grown, vol);
+
+    let blend = candidates
+        .filter(|(other, _, other_cubemap, _)| !std::ptr::eq(*other, vol) && *other_cubemap != cubemap)
+        .map(|(other, _, other_cubemap, _)| {
+            let other_coverage = coverage(&grown, other);
+            (other, other_cubemap, other_coverage / (own_coverage + other_coverage).max(1e-6))
+        })
+        .filter(|(_, _, factor)| *factor >= MIN_BLEND)
+        .max_by(|a, b| a.2.total_cmp(&b.2))
+        .map(|(other, other_cubemap, factor)| (other.parallax_cubemap(other_cubemap, false), factor.min(0.5)));
+
+    Some((vol.parallax_cubemap(cubemap, false), blend))
+}
+
+/// Nearest volume for a surface outside of all of them (`fallback`)
+pub fn nearest_parallax_volume(bound: &AABB, surface_normal: Vec3, pcc_volumes: &[ParallaxVolume]) -> Option<ParallaxCubemap> {
+    let origin = bound.center;
+    let vol = pcc_volumes.iter()
+        .filter(|vol| !vol.cubemaps_origins.is_empty())
+        .min_by(|a, b| {
+            let dist = |vol: &ParallaxVolume| math::sq_dist_point_aabb(origin, &vol.bounds());
+            dist(a).total_cmp(&dist(b))
+        })?;
+
+    let (cubemap, _) = best_cubemap(vol, origin, surface_normal);
+    Some(vol.parallax_cubemap(cubemap, true))
+}
+
+/// Plain (non-parallax) cubemap for surfaces without a volume, picked like VBSP does: a cubemap whose `sides` list
+/// has one of the surface sides wins, otherwise the closest visible one in front of the surface.
+/// Cubemaps with a `sides` list are only used for those sides. With `visible_only`, hidden cubemaps are never picked
+pub fn find_env_cubemap(side_ids: &[u32], bound: &AABB, surface_normal: Vec3, env_cubemaps: &[EnvCubemap], world: &CollisionWorld, visible_only: bool) -> Option<ParallaxCubemap> {
+    let assigned = env_cubemaps.iter()
+        .find(|cubemap| cubemap.sides.iter().any(|side| side_ids.contains(side)));
+
+    let origin = bound.center + surface_normal * 4.0;
+    let best = assigned.or_else(|| {
+        env_cubemaps.iter()
+            .filter(|cubemap| cubemap.sides.is_empty())
+            .map(|cubemap| (cubemap, tracer::is_occluded(origin, cubemap.origin, world)))
+            .filter(|(_, hidden)| !visible_only || !hidden)
+            .min_by_key(|(cubemap, hidden)| {
+                let to_cubemap = cubemap.origin - origin;
+                let behind = surface_normal.dot(to_cubemap) < 0.0;
+                (*hidden, behind, to_cubemap.dot(to_cubemap) as i64)
+            })
+            .map(|(cubemap, _)| cubemap)
+    })?;
+
+    Some(ParallaxCubemap {
+        cubemap_pos: best.origin,
+        ws_min: best.origin,
+        ws_max: best.origin,
+        volume: OrientedBox { center: best.origin, axes: OrientedBox::WORLD_AXES, half_extents: Vec3::ZERO },
+        fallback: false,
+        parallax: false,
+    })
+}
+
+/// VBSP name of the baked cubemap texture
+pub fn cubemap_vtf_name(map_name: &str, pos: Vec3) -> String {
+    format!("maps/{}/c{}_{}_{}.hdr.vtf", map_name, pos[0] as i32, pos[1] as i32, pos[2] as i32)
+}
+
+// Closest cubemap of the volume in front of the surface, and its score
+fn best_cubemap(vol: &ParallaxVolume, origin: Vec3, surface_normal: Vec3) -> (Vec3, f32) {
+    let mut best_score = f32::MIN;
+    let mut best_c = vol.cubemaps_origins[0];
+
+    for &c in &vol.cubemaps_origins {
+        let to_cubemap = c - origin;
+        let dist_sq = to_cubemap.dot(to_cubemap);
+        let mut score = -dist_sq;
+
+        let dir_to_cubemap = to_cubemap.normalize();
+        let facing = surface_normal.dot(dir_to_cubemap);
+
+        // Cubemap behind ur back? GFY!
+        if facing < 0.0 {
+            score -= 1_000_000.0;
+        } else {
+            score += facing * 100.0;
+        }
+
+        if score > best_score {
+            best_score = score;
+            best_c = c;
+        }
+    }
+
+    (best_c, best_score)
+}
+
+/// Part of `bound` inside the volume, 0..1. Flat sides of the bound (surfaces are thin) count as 1 unit thick.
+/// For rotated volumes the bound is measured along the volume axes
+fn coverage(bound: &AABB, vol: &ParallaxVolume) -> f32 {
+    let mut local = AABB::new();
+    for p in OrientedBox::from_aabb(bound).corners() {
+        local.extend(vol.obb.to_local(p));
+    }
+
+    let half = vol.obb.half_extents;
+    (0..3).map(|i| {
+        let (mut lo, mut hi) = (local.min[i], local.max[i]);
+        if hi - lo < 1.0 {
+            let mid = (lo + hi) * 0.5;
+            (lo, hi) = (mid - 0.5, mid + 0.5);
+        }
+        let overlap = hi.min(half[i]) - lo.max(-half[i]);
+        (overlap / (hi - lo)).clamp(0.0, 1.0)
+    })
+    .product()
+}
+
+
+#[derive(Debug, Default)]
+pub struct Cubemaps {
+    pub volumes: Vec<ParallaxVolume>,
+    pub env_cubemaps: Vec<EnvCubemap>,
+}
+
+impl Cubemaps {
+    /// Builds box volumes for env_cubemaps outside of every `func_parallax_volume`, from the walls around them.
+    /// Cubemaps in the same generated box share it, cubemaps with a `sides` list are left alone.
+    /// Returns the boxes of the new volumes
+    pub fn generate_volumes(&mut self, world: &CollisionWorld) -> Vec<OrientedBox> {
+        let mut generated: Vec<ParallaxVolume> = Vec::new();
+
+        for cubemap in self.env_cubemaps.iter().filter(|cubemap| cubemap.sides.is_empty()) {
+            if self.volumes.iter().any(|vol| vol.obb.contains(cubemap.origin)) {
+                continue;
+            }
+            if let Some(vol) = generated.iter_mut().find(|vol| vol.obb.contains(cubemap.origin)) {
+                vol.cubemaps_origins.push(cubemap.origin);
+                continue;
+            }
+
+            match trace_room(cubemap.origin, world) {
+                Some(obb) => {
+                    info!("Generated parallax volume for env_cubemap at {}: center {}, size {}", cubemap.origin, obb.center, obb.size());
+                    generated.push(ParallaxVolume { obb, cubemaps_origins: vec![cubemap.origin] });
                 }
-            //    break;
-        //    }
+                None => warn!("env_cubemap at {} isn't enclosed by walls, no parallax volume generated for it.", cubemap.origin),
+            }
+        }
+
+        let boxes = generated.iter().map(|vol| vol.obb).collect();
+        self.volumes.extend(generated);
+        boxes
     }
+}
 
-    best_pcc_data
+/// `func_parallax_volume` entity for a generated volume, to save it into the VMF
+pub fn volume_entity(obb: &OrientedBox, next_id: &mut u64) -> Entity {
+    let mut ent = Entity::new("func_parallax_volume", *next_id);
+    *next_id += 1;
+    ent.solids = Some(vec![geometry::box_solid(obb, "tools/toolstrigger", next_id)]);
+    ent
 }
 
+// Box around the hits of rays from `origin` along the 6 axes and 20 diagonals. None if any ray escapes
+fn trace_room(origin: Vec3, world: &CollisionWorld) -> Option<OrientedBox> {
+    let mut points = Vec::with_capacity(26);
+    for x in -1..=1 {
+        for y in -1..=1 {
+            for z in -1..=1 {
+                if (x, y, z) == (0, 0, 0) {
+                    continue;
+                }
+                let dir = Vec3::new(x as f32, y as f32, z as f32).normalize();
+                let hit = tracer::trace_ray_closest(origin, dir, AUTO_VOLUME_MAX_DIST, world)?;
+                points.push(origin + dir * hit.t);
+            }
+        }
+    }
 
+    // Diagonal rooms fit better into a box turned by 45 degrees
+    let aabb_box = OrientedBox::fit(&points, OrientedBox::WORLD_AXES);
+    let diagonal_box = OrientedBox::fit(&points, math::angles_to_basis(Vec3::new(0.0, 45.0, 0.0)));
+    Some(if diagonal_box.volume() < aabb_box.volume() * 0.99 { diagonal_box } else { aabb_box })
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvCubemap {
+    origin: Vec3,
+    // Brush sides this cubemap is bound to (`sides` key)
+    sides: Vec<u32>,
+}
+
+#[derive(Debug)]
 pub struct ParallaxVolume {
-    ws_min: Vec3,
-    ws_max: Vec3,
+    obb: OrientedBox,
     cubemaps_origins: Vec<Vec3>,
 }
 
-pub fn process_cubemaps(vmf: &VmfFile,) -> Vec<ParallaxVolume> {
-    let cubemaps_origin: Vec<Vec3> = vmf.entities
+impl ParallaxVolume {
+    fn bounds(&self) -> AABB {
+        self.obb.bounds()
+    }
+
+    fn parallax_cubemap(&self, cubemap_pos: Vec3, fallback: bool) -> ParallaxCubemap {
+        let bounds = self.bounds();
+        ParallaxCubemap {
+            cubemap_pos,
+            ws_min: bounds.min,
+            ws_max: bounds.max,
+            volume: self.obb,
+            fallback,
+            parallax: true,
+        }
+    }
+}
+
+/// Box of a `func_parallax_volume`. Its `angles` set the rotation, without them it's guessed from the brush faces
+fn volume_box(ent: &Entity) -> Option<OrientedBox> {
+    let angles = ent.get("angles").map(|s| Vec3::parse(s)).filter(|angles| *angles != Vec3::ZERO);
+    match angles {
+        Some(angles) => {
+            let points: Vec<Vec3> = ent.solids.as_ref()?.iter()
+                .flat_map(geometry::solid_faces)
+                .flat_map(|face| face.winding)
+                .collect();
+            (!points.is_empty()).then(|| OrientedBox::fit(&points, math::angles_to_basis(angles)))
+        }
+        None => geometry::get_entity_obb(ent),
+    }
+}
+
+pub fn process_cubemaps(vmf: &VmfFile,) -> Cubemaps {
+    let env_cubemaps: Vec<EnvCubemap> = vmf.entities
         .iter()
         .filter(|ent| ent.classname().unwrap_or("") == "env_cubemap")
-        .map(|ent| Vec3::parse(ent.get("origin").unwrap_or(&"0 0 0".to_string())))
+        .map(|ent| EnvCubemap {
+            origin: Vec3::parse(ent.get("origin").unwrap_or(&"0 0 0".to_string())),
+            sides: ent.get("sides")
+                .map(|sides| sides.split_whitespace().filter_map(|id| id.parse().ok()).collect())
+                .unwrap_or_default(),
+        })
         .collect();
 
-    if cubemaps_origin.is_empty() {
+    if env_cubemaps.is_empty() {
         warn!("No env_cubemaps found on the map!");
-        return Vec::new();
+        return Cubemaps::default();
     } else {
-        info!("Found {} env_cubemaps.", cubemaps_origin.len());
+        info!("Found {} env_cubemaps.", env_cubemaps.len());
     }
 
-    vmf.entities
+    let volumes = vmf.entities
         .iter()
         .filter(|ent| ent.classname().unwrap_or("") == "func_parallax_volume")
         .filter_map(|ent| {
-            let aabb = geometry::get_entity_aabb(ent)?;
-            let mut inside = Vec::new();
-            for &c_pos in &cubemaps_origin {
-                if c_pos[0] >= aabb.min[0] && c_pos[0] <= aabb.max[0] &&
-                    c_pos[1] >= aabb.min[1] && c_pos[1] <= aabb.max[1] &&
-                    c_pos[2] >= aabb.min[2] && c_pos[2] <= aabb.max[2] {
-                    inside.push(c_pos);
-                }
+            let obb = volume_box(ent)?;
+            let inside: Vec<Vec3> = env_cubemaps.iter()
+                .map(|c| c.origin)
+                .filter(|&c_pos| obb.contains(c_pos))
+                .collect();
+            if inside.is_empty() {
+                warn!("func_parallax_volume (id: {}) has no env_cubemap inside. Skipping it.", ent.id());
             }
 
             Some(ParallaxVolume {
-                ws_min: aabb.min,
-                ws_max: aabb.max,
+                obb,
                 cubemaps_origins: inside,
             })
         })
-        .collect()
+        .collect();
+
+    Cubemaps { volumes, env_cubemaps }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::geometry::ConvexBrush;
+
+    fn volume(min: Vec3, max: Vec3, cubemap: Vec3) -> ParallaxVolume {
+        let mut aabb = AABB::new();
+        aabb.extend(min);
+        aabb.extend(max);
+        ParallaxVolume { obb: OrientedBox::from_aabb(&aabb), cubemaps_origins: vec![cubemap] }
+    }
+
+    fn floor_at(x: f32) -> AABB {
+        let mut bound = AABB::new();
+        bound.extend(Vec3::new(x, 0.0, 0.0));
+        bound.extend(Vec3::new(x + 64.0, 64.0, 0.0));
+        bound
+    }
+
+    #[test]
+    fn test_volume_containing_the_surface_wins() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let volumes = vec![
+            volume(Vec3::new(0.0, 0.0, -64.0), Vec3::new(256.0, 256.0, 256.0), Vec3::new(128.0, 128.0, 64.0)),
+            volume(Vec3::new(512.0, 0.0, -64.0), Vec3::new(768.0, 256.0, 256.0), Vec3::new(640.0, 128.0, 64.0)),
+        ];
+
+        // The last volume used to win no matter where the surface was
+        let (pcc, blend) = find_parallax_volume(&floor_at(32.0), up, &volumes).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(128.0, 128.0, 64.0));
+        assert!(!pcc.fallback);
+        assert!(blend.is_none());
+
+        // Mostly in the second volume
+        let (pcc, _) = find_parallax_volume(&floor_at(480.0), up, &volumes).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(640.0, 128.0, 64.0));
+
+        // Outside of both, the nearest one is the fallback
+        assert!(find_parallax_volume(&floor_at(300.0), up, &volumes).is_none());
+        let pcc = nearest_parallax_volume(&floor_at(300.0), up, &volumes).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(128.0, 128.0, 64.0));
+        assert!(pcc.fallback);
+    }
+
+    #[test]
+    fn test_blend_across_doorway() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let volumes = vec![
+            volume(Vec3::new(0.0, 0.0, -64.0), Vec3::new(256.0, 256.0, 256.0), Vec3::new(128.0, 128.0, 64.0)),
+            volume(Vec3::new(256.0, 0.0, -64.0), Vec3::new(512.0, 256.0, 256.0), Vec3::new(384.0, 128.0, 64.0)),
+        ];
+
+        let (pcc, blend) = find_parallax_volume(&floor_at(200.0), up, &volumes).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(128.0, 128.0, 64.0));
+        let (other, factor) = blend.unwrap();
+        assert_eq!(other.cubemap_pos, Vec3::new(384.0, 128.0, 64.0));
+        assert!((factor - 40.0 / 128.0).abs() < 1e-4);
+
+        // Deep inside the room, no blending
+        let (_, blend) = find_parallax_volume(&floor_at(64.0), up, &volumes).unwrap();
+        assert!(blend.is_none());
+    }
+
+    #[test]
+    fn test_rotated_volume() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        // Diagonal corridor, 1024 long and 128 wide
+        let corridor = OrientedBox::fit(
+            &[Vec3::new(0.0, -64.0, -64.0), Vec3::new(1024.0, 64.0, 256.0)].map(|p| {
+                let basis = math::angles_to_basis(Vec3::new(0.0, 45.0, 0.0));
+                basis[0] * p[0] + basis[1] * p[1] + basis[2] * p[2]
+            }),
+            math::angles_to_basis(Vec3::new(0.0, 45.0, 0.0)),
+        );
+        let volumes = vec![
+            ParallaxVolume { obb: corridor, cubemaps_origins: vec![Vec3::new(362.0, 362.0, 64.0)] },
+            volume(Vec3::new(0.0, 600.0, -64.0), Vec3::new(256.0, 856.0, 256.0), Vec3::new(128.0, 728.0, 64.0)),
+        ];
+
+        // Inside the corridor AABB, but not in the corridor itself
+        assert_eq!(coverage(&floor_at(600.0), &volumes[0]), 0.0);
+        let mut on_axis = AABB::new();
+        on_axis.extend(Vec3::new(340.0, 340.0, 0.0));
+        on_axis.extend(Vec3::new(380.0, 380.0, 0.0));
+        let (pcc, _) = find_parallax_volume(&on_axis, up, &volumes).unwrap();
+        assert!(!pcc.fallback);
+        assert_eq!(pcc.cubemap_pos, Vec3::new(362.0, 362.0, 64.0));
+
+        // The transform maps the volume onto the unit box
+        let rows = pcc.volume.world_to_unit_box();
+        let far_corner = corridor.center + corridor.axes[0] * corridor.half_extents[0];
+        let x = rows[0][0] * far_corner[0] + rows[0][1] * far_corner[1] + rows[0][2] * far_corner[2] + rows[0][3];
+        assert!((x - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_generate_volume_from_walls() {
+        // 512x512x256 room with 16 unit walls
+        let mut next_id = 1;
+        let walls: Vec<ConvexBrush> = [
+            (Vec3::new(-272.0, -272.0, -16.0), Vec3::new(272.0, 272.0, 0.0)),
+            (Vec3::new(-272.0, -272.0, 256.0), Vec3::new(272.0, 272.0, 272.0)),
+            (Vec3::new(-272.0, -272.0, 0.0), Vec3::new(-256.0, 272.0, 256.0)),
+            (Vec3::new(256.0, -272.0, 0.0), Vec3::new(272.0, 272.0, 256.0)),
+            (Vec3::new(-256.0, -272.0, 0.0), Vec3::new(256.0, -256.0, 256.0)),
+            (Vec3::new(-256.0, 256.0, 0.0), Vec3::new(256.0, 272.0, 256.0)),
+        ]
+        .iter()
+        .map(|&(min, max)| {
+            let mut aabb = AABB::new();
+            aabb.extend(min);
+            aabb.extend(max);
+            let solid = geometry::box_solid(&OrientedBox::from_aabb(&aabb), "tools/toolsnodraw", &mut next_id);
+            ConvexBrush::from_vmf_solid(&solid).unwrap()
+        })
+        .collect();
+        let world = CollisionWorld::new(walls, Vec::new());
+
+        let mut cubemaps = Cubemaps {
+            volumes: Vec::new(),
+            env_cubemaps: vec![
+                EnvCubemap { origin: Vec3::new(100.0, 50.0, 64.0), sides: Vec::new() },
+                EnvCubemap { origin: Vec3::new(-100.0, 0.0, 128.0), sides: Vec::new() },
+            ],
+        };
+        let generated = cubemaps.generate_volumes(&world);
+
+        // Both cubemaps share one volume, which is the room
+        assert_eq!(generated.len(), 1);
+        assert_eq!(cubemaps.volumes[0].cubemaps_origins.len(), 2);
+        let bounds = generated[0].bounds();
+        assert!((bounds.min - Vec3::new(-256.0, -256.0, 0.0)).length() < 0.1);
+        assert!((bounds.max - Vec3::new(256.0, 256.0, 256.0)).length() < 0.1);
+
+        // Saved into the VMF, it reads back as the same box
+        let ent = volume_entity(&generated[0], &mut next_id);
+        let obb = volume_box(&ent).unwrap();
+        assert!((obb.center - generated[0].center).length() < 0.1);
+        assert!((obb.size() - generated[0].size()).length() < 0.1);
+    }
+
+    #[test]
+    fn test_env_cubemap_sides_and_facing() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let cubemap = |origin: Vec3, sides: Vec<u32>| EnvCubemap { origin, sides };
+        let env_cubemaps = vec![
+            cubemap(Vec3::new(32.0, 32.0, -32.0), Vec::new()),
+            cubemap(Vec3::new(32.0, 32.0, 256.0), Vec::new()),
+            cubemap(Vec3::new(32.0, 32.0, 8.0), vec![7]),
+        ];
+        let world = CollisionWorld::new(Vec::new(), Vec::new());
+
+        // The closest one is under the floor, and the bound one is for another side
+        let pcc = find_env_cubemap(&[1, 2], &floor_at(0.0), up, &env_cubemaps, &world, false).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(32.0, 32.0, 256.0));
+        assert!(!pcc.parallax);
+
+        let pcc = find_env_cubemap(&[7], &floor_at(0.0), up, &env_cubemaps, &world, false).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(32.0, 32.0, 8.0));
+    }
+
+    #[test]
+    fn test_visible_env_cubemap_beats_distant_volume() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let mut next_id = 1;
+        let mut aabb = AABB::new();
+        aabb.extend(Vec3::new(-64.0, -64.0, 64.0));
+        aabb.extend(Vec3::new(128.0, 128.0, 80.0));
+        let ceiling = geometry::box_solid(&OrientedBox::from_aabb(&aabb), "tools/toolsnodraw", &mut next_id);
+        let world = CollisionWorld::new(vec![ConvexBrush::from_vmf_solid(&ceiling).unwrap()], Vec::new());
+
+        let mut cubemaps = Cubemaps {
+            volumes: vec![volume(Vec3::new(1024.0, 0.0, -64.0), Vec3::new(1280.0, 256.0, 256.0), Vec3::new(1152.0, 128.0, 64.0))],
+            env_cubemaps: vec![EnvCubemap { origin: Vec3::new(32.0, 32.0, 32.0), sides: Vec::new() }],
+        };
+
+        // Nothing covers the floor, so the env_cubemap above it wins over the far volume
+        let (pcc, blend) = select_cubemap(&[1], &floor_at(0.0), up, &cubemaps, &world).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(32.0, 32.0, 32.0));
+        assert!(!pcc.parallax);
+        assert!(blend.is_none());
+
+        // Behind the ceiling it is hidden, and the nearest volume is used
+        cubemaps.env_cubemaps[0].origin = Vec3::new(32.0, 32.0, 128.0);
+        let (pcc, _) = select_cubemap(&[1], &floor_at(0.0), up, &cubemaps, &world).unwrap();
+        assert_eq!(pcc.cubemap_pos, Vec3::new(1152.0, 128.0, 64.0));
+        assert!(pcc.fallback);
+    }
 }
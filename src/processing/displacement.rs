@@ -0,0 +1,293 @@
+use crate::math::{AABB, Vec3};
+use super::bvh::Bvh;
+use super::geometry::{self, Plane};
+use log::{debug, warn};
+use vmf_forge::prelude::{DispInfo, Solid};
+
+/// Triangulated displacement surface. Unlike brushes it has no volume, so it is tested per triangle
+#[derive(Debug, Clone)]
+pub struct DispMesh {
+    pub id: u64,
+    pub material: String,
+    pub u_axis: String,
+    pub v_axis: String,
+    pub triangles: Vec<[Vec3; 3]>,
+    pub bounds: AABB,
+    pub(crate) bvh: Bvh,
+}
+
+/// Per-vertex displacement data, parsed from `dispinfo` rows
+#[derive(Debug, Clone)]
+pub struct DispGrid {
+    pub power: u32,
+    pub start_position: Vec3,
+    pub elevation: f32,
+    pub normals: Vec<Vec3>,
+    pub distances: Vec<f32>,
+    pub offsets: Vec<Vec3>,
+}
+
+impl DispGrid {
+    pub fn from_dispinfo(info: &DispInfo) -> Self {
+        let start = info.start_position.replace(['[', ']'], "");
+
+        Self {
+            power: info.power as u32,
+            start_position: Vec3::parse(&start),
+            elevation: info.elevation,
+            normals: parse_vec_rows(&info.normals.rows),
+            distances: parse_float_rows(&info.distances.rows),
+            offsets: info.offsets.as_ref().map(|o| parse_vec_rows(&o.rows)).unwrap_or_default(),
+        }
+    }
+
+    /// Number of vertices along one edge (2^power + 1)
+    pub fn size(&self) -> usize {
+        (1 << self.power) + 1
+    }
+
+    /// Builds the displaced vertex grid (row-major) on top of the 4 face corners
+    pub fn build_vertices(&self, corners: &[Vec3; 4], face_normal: Vec3) -> Vec<Vec3> {
+        // The first corner is the one closest to 'startposition', like VBSP does
+        let start_idx = (0..4)
+            .min_by(|&a, &b| {
+                corners[a].distance(self.start_position).total_cmp(&corners[b].distance(self.start_position))
+            })
+            .unwrap_or(0);
+        let p: [Vec3; 4] = std::array::from_fn(|i| corners[(start_idx + i) % 4]);
+
+        let size = self.size();
+        let step = 1.0 / (size - 1) as f32;
+        let mut vertices = Vec::with_capacity(size * size);
+
+        for row in 0..size {
+            let edge_start = p[0].lerp(p[1], row as f32 * step);
+            let edge_end = p[3].lerp(p[2], row as f32 * step);
+
+            for col in 0..size {
+                let idx = row * size + col;
+                let base = edge_start.lerp(edge_end, col as f32 * step);
+                let normal = self.normals.get(idx).copied().unwrap_or(Vec3::ZERO);
+                let dist = self.distances.get(idx).copied().unwrap_or(0.0);
+                let offset = self.offsets.get(idx).copied().unwrap_or(Vec3::ZERO);
+
+                vertices.push(base + normal * dist + offset + face_normal * self.elevation);
+            }
+        }
+
+        vertices
+    }
+
+    /// Splits the grid into triangles, alternating the quad diagonal like VBSP does
+    pub fn triangulate(&self, vertices: &[Vec3]) -> Vec<[Vec3; 3]> {
+        let size = self.size();
+        let mut triangles = Vec::with_capacity((size - 1) * (size - 1) * 2);
+
+        for row in 0..size - 1 {
+            for col in 0..size - 1 {
+                let v00 = vertices[row * size + col];
+                let v01 = vertices[row * size + col + 1];
+                let v10 = vertices[(row + 1) * size + col];
+                let v11 = vertices[(row + 1) * size + col + 1];
+
+                if (row + col) % 2 == 0 {
+                    triangles.push([v00, v10, v11]);
+                    triangles.push([v00, v11, v01]);
+                } else {
+                    triangles.push([v00, v10, v01]);
+                    triangles.push([v10, v11, v01]);
+                }
+            }
+        }
+
+        triangles
+    }
+}
+
+impl DispMesh {
+    pub fn new(id: u64, material: String, u_axis: String, v_axis: String, triangles: Vec<[Vec3; 3]>) -> Self {
+        let mut bounds = AABB::new();
+        let tri_bounds: Vec<AABB> = triangles.iter().map(|tri| {
+            let mut b = AABB::new();
+            tri.iter().for_each(|&v| b.extend(v));
+            bounds.extend(b.min);
+            bounds.extend(b.max);
+            b
+        }).collect();
+
+        Self {
+            id,
+            material,
+            u_axis,
+            v_axis,
+            bvh: Bvh::build(&tri_bounds),
+            triangles,
+            bounds,
+        }
+    }
+
+    /// Triangulates every displacement side of a VMF solid. Returns nothing for regular solids
+    pub fn from_vmf_solid(solid: &Solid) -> Vec<Self> {
+        if !solid.sides.iter().any(|s| s.dispinfo.is_some()) {
+            return Vec::new();
+        }
+
+        let planes: Option<Vec<Plane>> = solid.sides.iter().map(Plane::from_side).collect();
+        let Some(planes) = planes else {
+            warn!("Displacement solid ID {}: Malformed plane definition found. Skipping.", solid.id);
+            return Vec::new();
+        };
+
+        let mut meshes = Vec::new();
+        for (idx, side) in solid.sides.iter().enumerate() {
+            let Some(info) = &side.dispinfo else { continue };
+
+            let winding = geometry::face_winding(&planes, idx);
+            let Ok(corners) = <[Vec3; 4]>::try_from(winding) else {
+                warn!("Displacement solid ID {}: displacement face is not a quad. Skipping.", solid.id);
+                continue;
+            };
+
+            let grid = DispGrid::from_dispinfo(info);
+            let vertex_count = grid.size() * grid.size();
+            if grid.normals.len() != vertex_count || grid.distances.len() != vertex_count {
+                warn!("Displacement solid ID {}: expected {} vertices for power {}, got {} normals and {} distances",
+                    solid.id, vertex_count, grid.power, grid.normals.len(), grid.distances.len());
+            }
+
+            let vertices = grid.build_vertices(&corners, planes[idx].normal);
+            let triangles = grid.triangulate(&vertices);
+            debug!("Created DispMesh for solid ID {} (power {}, {} triangles)", solid.id, grid.power, triangles.len());
+
+            meshes.push(DispMesh::new(solid.id, side.material.clone(), side.u_axis.clone(), side.v_axis.clone(), triangles));
+        }
+
+        meshes
+    }
+}
+
+fn parse_float_rows(rows: &[String]) -> Vec<f32> {
+    rows.iter()
+        .flat_map(|row| row.split_whitespace().filter_map(|v| v.parse::<f32>().ok()))
+        .collect()
+}
+
+fn parse_vec_rows(rows: &[String]) -> Vec<Vec3> {
+    parse_float_rows(rows)
+        .chunks_exact(3)
+        .map(|c| Vec3::new(c[0], c[1], c[2]))
+        .collect()
+}
+
+/// Möller–Trumbore ray/triangle intersection. Returns the distance along `dir`
+pub fn intersect_triangle(origin: Vec3, dir: Vec3, tri: &[Vec3; 3]) -> Option<f32> {
+    let edge1 = tri[1] - tri[0];
+    let edge2 = tri[2] - tri[0];
+    let pvec = dir.cross(edge2);
+    let det = edge1.dot(pvec);
+    if det.abs() < 1e-8 {
+        return None; // Parallel to the triangle
+    }
+
+    let inv_det = 1.0 / det;
+    let tvec = origin - tri[0];
+    let u = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let v = dir.dot(qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    Some(edge2.dot(qvec) * inv_det)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 256x256 floor at z=0, corners in VMF order
+    fn floor_corners() -> [Vec3; 4] {
+        [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 256.0, 0.0),
+            Vec3::new(256.0, 256.0, 0.0),
+            Vec3::new(256.0, 0.0, 0.0),
+        ]
+    }
+
+    fn flat_grid(power: u32) -> DispGrid {
+        let size = (1 << power) + 1;
+        DispGrid {
+            power,
+            start_position: Vec3::ZERO,
+            elevation: 0.0,
+            normals: vec![Vec3::new(0.0, 0.0, 1.0); size * size],
+            distances: vec![0.0; size * size],
+            offsets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_face_winding_of_box_top() {
+        // 256x256x64 box, normals point outside
+        let planes = vec![
+            Plane::new(Vec3::new(0.0, 0.0, 1.0), 0.0),
+            Plane::new(Vec3::new(0.0, 0.0, -1.0), -64.0),
+            Plane::new(Vec3::new(1.0, 0.0, 0.0), -256.0),
+            Plane::new(Vec3::new(-1.0, 0.0, 0.0), 0.0),
+            Plane::new(Vec3::new(0.0, 1.0, 0.0), -256.0),
+            Plane::new(Vec3::new(0.0, -1.0, 0.0), 0.0),
+        ];
+        let winding = geometry::face_winding(&planes, 0);
+        assert_eq!(winding.len(), 4);
+        for corner in floor_corners() {
+            assert!(winding.iter().any(|p| p.distance(corner) < 0.01), "Missing corner {}", corner);
+        }
+
+        // Same orientation as VMF plane points
+        let n = (winding[1] - winding[0]).cross(winding[2] - winding[0]);
+        assert!(n[2] < 0.0);
+    }
+
+    #[test]
+    fn test_grid_size_and_corners() {
+        let grid = flat_grid(3);
+        let vertices = grid.build_vertices(&floor_corners(), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(vertices.len(), 81);
+        assert_eq!(vertices[0], Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(vertices[80], Vec3::new(256.0, 256.0, 0.0));
+        assert_eq!(grid.triangulate(&vertices).len(), 128);
+    }
+
+    #[test]
+    fn test_start_position_rotates_grid() {
+        let mut grid = flat_grid(2);
+        grid.start_position = Vec3::new(256.0, 256.0, 0.0);
+        let vertices = grid.build_vertices(&floor_corners(), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(vertices[0], Vec3::new(256.0, 256.0, 0.0));
+    }
+
+    #[test]
+    fn test_raised_vertex_occludes() {
+        // Raise the middle of a power 2 displacement into a 128 units high hill
+        let mut grid = flat_grid(2);
+        grid.distances[12] = 128.0;
+        let vertices = grid.build_vertices(&floor_corners(), Vec3::new(0.0, 0.0, 1.0));
+        let mesh = DispMesh::new(1, "nature/grass".to_string(), String::new(), String::new(), grid.triangulate(&vertices));
+        let world = super::super::geometry::CollisionWorld::new(Vec::new(), vec![mesh]);
+
+        // Through the hill
+        assert!(crate::tracer::is_occluded(Vec3::new(-64.0, 128.0, 32.0), Vec3::new(320.0, 128.0, 32.0), &world));
+        // Over the hill
+        assert!(!crate::tracer::is_occluded(Vec3::new(-64.0, 128.0, 160.0), Vec3::new(320.0, 128.0, 160.0), &world));
+
+        let hit = crate::tracer::trace_ray_closest(Vec3::new(128.0, 128.0, 512.0), Vec3::new(0.0, 0.0, -1.0), 1024.0, &world)
+            .expect("Ray straight down should hit the hill top");
+        assert_eq!(hit.id, 1);
+        assert!((hit.t - 384.0).abs() < 0.01);
+    }
+}
@@ -1,8 +1,10 @@
-use crate::math::{AABB, Vec3};
+use crate::math::{AABB, OrientedBox, Vec3};
+use super::bvh::Bvh;
+use super::displacement::DispMesh;
 use crate::{GEOMETRY_OFFSET_UNITS, LightCluster, TARGET_MATERIAL, UV_SEARCH_DIST, utils};
 use log::{debug, info, warn};
 use vmf_forge::VmfFile;
-use vmf_forge::prelude::{Entity, Solid};
+use vmf_forge::prelude::{Entity, Side, Solid};
 
 #[derive(Debug, Clone)]
 pub struct Plane {
@@ -23,6 +25,248 @@ impl Plane {
             material: String::from("default"),
         }
     }
+
+    /// Builds a plane from a VMF side. The normal points out of the solid
+    pub fn from_side(side: &Side) -> Option<Self> {
+        let points = utils::text::parse_plane_points(&side.plane)?;
+        let normal = utils::text::calc_face_normal(points) * -1.0;
+
+        Some(Plane {
+            normal,
+            dist: -normal.dot(points[0]),
+            u_axis: side.u_axis.clone(),
+            v_axis: side.v_axis.clone(),
+            material: side.material.clone(),
+        })
+    }
+
+    /// Signed distance from the plane, positive outside of the solid
+    pub fn distance_to(&self, point: Vec3) -> f32 {
+        self.normal.dot(point) + self.dist
+    }
+}
+
+// Half-size of the initial quad used to build face windings (bigger than any Source map)
+const WINDING_EXTENT: f32 = 65536.0;
+const WINDING_EPSILON: f32 = 0.01;
+
+/// Builds the polygon of `planes[face_idx]` by clipping a huge quad against all other planes of a convex solid.
+/// Points are ordered like VMF plane points (clockwise when looking at the face from outside).
+/// Returns less than 3 points if the face is degenerate.
+pub fn face_winding(planes: &[Plane], face_idx: usize) -> Vec<Vec3> {
+    let face = &planes[face_idx];
+    let normal = face.normal;
+    let origin = normal * -face.dist;
+
+    let up_base = if normal[2].abs() > 0.9 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
+    let right = up_base.cross(normal).normalize() * WINDING_EXTENT;
+    let up = normal.cross(right).normalize() * WINDING_EXTENT;
+
+    let mut winding = vec![origin - right + up, origin + right + up, origin + right - up, origin - right - up];
+    if (winding[1] - winding[0]).cross(winding[2] - winding[0]).dot(normal) > 0.0 {
+        winding.reverse();
+    }
+
+    for (i, plane) in planes.iter().enumerate() {
+        if i == face_idx || winding.len() < 3 {
+            continue;
+        }
+        winding = clip_winding(&winding, plane);
+    }
+
+    winding
+}
+
+/// Polygon of a single solid side
+#[derive(Debug, Clone)]
+pub struct BrushFace {
+    pub side_idx: usize,
+    pub plane: Plane,
+    pub winding: Vec<Vec3>,
+}
+
+/// Oriented rectangle lying on a face. `up` is `right x normal`, matching the shader basis
+#[derive(Debug, Clone, Copy)]
+pub struct FaceRect {
+    pub center: Vec3,
+    pub normal: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BrushFace {
+    pub fn area(&self) -> f32 {
+        let w = &self.winding;
+        let mut doubled = Vec3::ZERO;
+        for i in 1..w.len().saturating_sub(1) {
+            doubled = doubled + (w[i] - w[0]).cross(w[i + 1] - w[0]);
+        }
+        doubled.length() * 0.5
+    }
+
+    /// Smallest rectangle on the face plane that encloses the polygon.
+    /// One of the polygon edges is always aligned with it, so only edge directions are tested
+    pub fn bounding_rect(&self) -> FaceRect {
+        let normal = self.plane.normal;
+        let mut best: Option<(f32, FaceRect)> = None;
+
+        for i in 0..self.winding.len() {
+            let edge = self.winding[(i + 1) % self.winding.len()] - self.winding[i];
+            if edge.length() < WINDING_EPSILON {
+                continue;
+            }
+
+            let right = edge.normalize();
+            let up = right.cross(normal).normalize();
+            let (mut r_min, mut r_max, mut u_min, mut u_max) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+            for &p in &self.winding {
+                let (r, u) = (p.dot(right), p.dot(up));
+                r_min = r_min.min(r);
+                r_max = r_max.max(r);
+                u_min = u_min.min(u);
+                u_max = u_max.max(u);
+            }
+
+            let (width, height) = (r_max - r_min, u_max - u_min);
+            if best.as_ref().is_some_and(|(area, _)| *area <= width * height + WINDING_EPSILON) {
+                continue;
+            }
+
+            // Center in the (right, up) coordinates, pushed back onto the face plane
+            let flat = right * ((r_min + r_max) * 0.5) + up * ((u_min + u_max) * 0.5);
+            let center = flat - normal * self.plane.distance_to(flat);
+            best = Some((width * height, FaceRect { center, normal, right, up, width, height }));
+        }
+
+        best.map(|(_, rect)| rect).unwrap_or(FaceRect {
+            center: self.winding.first().copied().unwrap_or(Vec3::ZERO),
+            normal,
+            right: Vec3::ZERO,
+            up: Vec3::ZERO,
+            width: 0.0,
+            height: 0.0,
+        })
+    }
+}
+
+/// Builds polygons for all sides of a solid. Malformed and degenerate sides are skipped
+pub fn solid_faces(solid: &Solid) -> Vec<BrushFace> {
+    let planes: Vec<(usize, Plane)> = solid.sides.iter()
+        .enumerate()
+        .filter_map(|(i, side)| Plane::from_side(side).map(|p| (i, p)))
+        .collect();
+    let only_planes: Vec<Plane> = planes.iter().map(|(_, p)| p.clone()).collect();
+
+    planes.into_iter()
+        .enumerate()
+        .filter_map(|(idx, (side_idx, plane))| {
+            let winding = face_winding(&only_planes, idx);
+            (winding.len() >= 3).then_some(BrushFace { side_idx, plane, winding })
+        })
+        .collect()
+}
+
+/// Cuts a convex solid in two by the plane through `point`. The first part is behind the plane (against `normal`).
+/// Sides that no longer touch a part are dropped, the new cap side gets `material`.
+/// Both parts keep the ids of `solid` and the caps get id 0, the caller has to renumber them.
+/// Returns None if the plane doesn't cross the solid
+pub fn split_solid(solid: &Solid, point: Vec3, normal: Vec3, material: &str) -> Option<(Solid, Solid)> {
+    let template = solid.sides.first()?;
+    let normal = normal.normalize();
+
+    let part = |outward: Vec3| -> Option<Solid> {
+        let cap = plane_side(template, point, outward, material);
+        let mut sides: Vec<&Side> = solid.sides.iter().collect();
+        sides.push(&cap);
+
+        let planes: Vec<Plane> = sides.iter().filter_map(|side| Plane::from_side(side)).collect();
+        if planes.len() != sides.len() {
+            return None; // Malformed side, leave the solid alone
+        }
+
+        let mut kept = Vec::with_capacity(sides.len());
+        for (idx, side) in sides.iter().enumerate() {
+            if face_winding(&planes, idx).len() >= 3 {
+                kept.push((*side).clone());
+            } else if idx == sides.len() - 1 {
+                return None;
+            }
+        }
+        Some(Solid { sides: kept, ..solid.clone() })
+    };
+
+    Some((part(normal)?, part(normal * -1.0)?))
+}
+
+/// New side through `point`, facing `outward`. Other side data (lightmap scale, etc.) comes from `template`
+fn plane_side(template: &Side, point: Vec3, outward: Vec3, material: &str) -> Side {
+    let up_base = if outward[2].abs() > 0.9 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
+    let u = up_base.cross(outward).normalize();
+    let v = outward.cross(u).normalize();
+
+    // VMF winding order: (p1 - p0) x (p2 - p0) points into the solid
+    let points = [point, point + v * 64.0, point + u * 64.0];
+    Side {
+        id: 0,
+        plane: utils::text::format_plane_points(points),
+        material: material.to_string(),
+        u_axis: format!("[{} {} {} 0] 0.25", u[0], u[1], u[2]),
+        v_axis: format!("[{} {} {} 0] 0.25", -v[0], -v[1], -v[2]),
+        dispinfo: None,
+        ..template.clone()
+    }
+}
+
+/// Box brush with `material` on every side. Plane points lie on the box corners. Takes ids from `next_id`
+pub fn box_solid(obb: &OrientedBox, material: &str, next_id: &mut u64) -> Solid {
+    let template = Side { lightmap_scale: 16, ..Default::default() };
+    let mut solid = Solid { id: *next_id, ..Default::default() };
+    *next_id += 1;
+
+    for i in 0..6 {
+        let (axis, sign) = (i % 3, if i < 3 { 1.0 } else { -1.0 });
+        let outward = obb.axes[axis] * sign;
+        let center = obb.center + outward * obb.half_extents[axis];
+        let a = obb.axes[(axis + 1) % 3] * obb.half_extents[(axis + 1) % 3];
+        let b = obb.axes[(axis + 2) % 3] * obb.half_extents[(axis + 2) % 3];
+
+        // VMF winding order: (p1 - p0) x (p2 - p0) points into the solid
+        let (p0, p1, p2) = (center - a - b, center - a + b, center + a - b);
+        let points = if sign > 0.0 { [p0, p1, p2] } else { [p0, p2, p1] };
+
+        solid.sides.push(Side {
+            id: *next_id as u32,
+            plane: utils::text::format_plane_points(points),
+            ..plane_side(&template, center, outward, material)
+        });
+        *next_id += 1;
+    }
+
+    solid
+}
+
+/// Keeps the part of the polygon behind the plane (Sutherland-Hodgman)
+fn clip_winding(winding: &[Vec3], plane: &Plane) -> Vec<Vec3> {
+    let mut result = Vec::with_capacity(winding.len() + 1);
+
+    for i in 0..winding.len() {
+        let cur = winding[i];
+        let next = winding[(i + 1) % winding.len()];
+        let d_cur = plane.distance_to(cur);
+        let d_next = plane.distance_to(next);
+
+        if d_cur <= WINDING_EPSILON {
+            result.push(cur);
+        }
+        if (d_cur > WINDING_EPSILON && d_next < -WINDING_EPSILON) || (d_cur < -WINDING_EPSILON && d_next > WINDING_EPSILON) {
+            let t = d_cur / (d_cur - d_next);
+            result.push(cur.lerp(next, t));
+        }
+    }
+
+    result
 }
 
 #[derive(Debug, Clone)]
@@ -91,6 +335,28 @@ impl ConvexBrush {
     }
 }
 
+/// Static geometry used for occlusion tests, with BVHs over the brush and displacement bounds
+#[derive(Debug, Clone, Default)]
+pub struct CollisionWorld {
+    pub brushes: Vec<ConvexBrush>,
+    pub displacements: Vec<DispMesh>,
+    pub(crate) bvh: Bvh,
+    pub(crate) disp_bvh: Bvh,
+}
+
+impl CollisionWorld {
+    pub fn new(brushes: Vec<ConvexBrush>, displacements: Vec<DispMesh>) -> Self {
+        let bounds: Vec<AABB> = brushes.iter().map(|b| b._bounds).collect();
+        let disp_bounds: Vec<AABB> = displacements.iter().map(|d| d.bounds).collect();
+        Self {
+            bvh: Bvh::build(&bounds),
+            disp_bvh: Bvh::build(&disp_bounds),
+            brushes,
+            displacements,
+        }
+    }
+}
+
 pub fn get_entity_aabb(ent: &Entity) -> Option<AABB> {
     let solids = ent.solids.as_ref()?;
     if solids.is_empty() { return None; }
@@ -114,6 +380,46 @@ pub fn get_entity_aabb(ent: &Entity) -> Option<AABB> {
     Some(aabb)
 }
 
+/// Oriented box around the entity brushes, with axes taken from the face normals.
+/// The largest face gives the first axis, the largest face across it gives the second.
+/// Falls back to the AABB when the rotated box isn't any tighter
+pub fn get_entity_obb(ent: &Entity) -> Option<OrientedBox> {
+    let aabb = get_entity_aabb(ent)?;
+    let faces: Vec<BrushFace> = ent.solids.as_ref()?.iter().flat_map(solid_faces).collect();
+    let points: Vec<Vec3> = faces.iter().flat_map(|f| f.winding.iter().copied()).collect();
+    if points.is_empty() {
+        return Some(OrientedBox::from_aabb(&aabb));
+    }
+
+    let areas: Vec<f32> = faces.iter().map(BrushFace::area).collect();
+    let (first_idx, _) = areas.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+    let axis_x = faces[first_idx].plane.normal;
+
+    // Weighted by how perpendicular the face is, so near-parallel faces don't win
+    let axis_y = faces.iter().zip(&areas)
+        .map(|(f, area)| {
+            let n = f.plane.normal;
+            let perp = n - axis_x * n.dot(axis_x);
+            (perp.length() * area, perp)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .filter(|(weight, _)| *weight > 0.0)
+        .map(|(_, perp)| perp.normalize());
+
+    let Some(axis_y) = axis_y else {
+        return Some(OrientedBox::from_aabb(&aabb));
+    };
+    let axis_z = axis_x.cross(axis_y).normalize();
+
+    let obb = OrientedBox::fit(&points, [axis_x, axis_y, axis_z]);
+    let aabb_box = OrientedBox::from_aabb(&aabb);
+    if obb.volume() < aabb_box.volume() * 0.99 {
+        Some(obb)
+    } else {
+        Some(aabb_box)
+    }
+}
+
 // todo!!!!!!!!!!! DRY
 pub fn get_solid_aabb(solid: &Solid) -> Option<AABB> {
     // Re-use logic from ConvexBrush parsing but for AABB
@@ -134,9 +440,10 @@ pub fn get_solid_aabb(solid: &Solid) -> Option<AABB> {
 }
 
 /// Builds the collision world from VMF solids and func_details
-pub fn build_collision_world(vmf: &VmfFile) -> Vec<ConvexBrush> {
+pub fn build_collision_world(vmf: &VmfFile) -> CollisionWorld {
     debug!("Building collision world...");
     let mut brushes = Vec::new();
+    let mut displacements = Vec::new();
 
     // World Solids (worldspawn)
     debug!("Processing {} world solids...", vmf.world.solids.len());
@@ -144,6 +451,7 @@ pub fn build_collision_world(vmf: &VmfFile) -> Vec<ConvexBrush> {
         if let Some(brush) = ConvexBrush::from_vmf_solid(solid) {
             brushes.push(brush);
         }
+        displacements.extend(DispMesh::from_vmf_solid(solid));
     }
 
     // Func Detail
@@ -160,20 +468,21 @@ pub fn build_collision_world(vmf: &VmfFile) -> Vec<ConvexBrush> {
                     if let Some(brush) = ConvexBrush::from_vmf_solid(solid) {
                         brushes.push(brush);
                     }
+                    displacements.extend(DispMesh::from_vmf_solid(solid));
                 }
             }
         }
     }
 
-    info!("Built collision world with {} brushes.", brushes.len());
-    brushes
+    info!("Built collision world with {} brushes and {} displacements.", brushes.len(), displacements.len());
+    CollisionWorld::new(brushes, displacements)
 }
 
 /// Offsets solid geometry to prevent z-fighting and copies UVs from parent surfaces
 pub fn apply_offsets_and_uv_fixes(
     clusters: &[LightCluster],
     map_name: &str,
-    world_brushes: &[ConvexBrush],
+    world_brushes: &CollisionWorld,
 ) {
     for cluster in clusters {
         // eg. "maps/sp_a2_triple_laser/surface_0_solid_0"
@@ -207,7 +516,7 @@ pub fn apply_offsets_and_uv_fixes(
             debug!("  [UV Fix] Casting ray from {:?} dir {:?} (dist: {})", start_pos, normal, UV_SEARCH_DIST);
 
             let ray_dir = normal * -1.0;
-            let parent_uv = crate::tracer::trace_ray_closest(start_pos, ray_dir, UV_SEARCH_DIST, &world_brushes)
+            let parent_uv = crate::tracer::trace_ray_closest(start_pos, ray_dir, UV_SEARCH_DIST, world_brushes)
                 .inspect(|hit| debug!("    -> x {:.2} (brush id: {}). Copying UVs.", hit.t, hit.id))
                 .map(|hit| {
                     (hit.u_axis, hit.v_axis)
@@ -244,3 +553,102 @@ pub fn apply_offsets_and_uv_fixes(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Box from `min` to `max`, normals point outside
+    fn box_planes(min: Vec3, max: Vec3) -> Vec<Plane> {
+        vec![
+            Plane::new(Vec3::new(0.0, 0.0, 1.0), -max[2]),
+            Plane::new(Vec3::new(0.0, 0.0, -1.0), min[2]),
+            Plane::new(Vec3::new(1.0, 0.0, 0.0), -max[0]),
+            Plane::new(Vec3::new(-1.0, 0.0, 0.0), min[0]),
+            Plane::new(Vec3::new(0.0, 1.0, 0.0), -max[1]),
+            Plane::new(Vec3::new(0.0, -1.0, 0.0), min[1]),
+        ]
+    }
+
+    #[test]
+    fn test_bounding_rect_of_box_bottom() {
+        let planes = box_planes(Vec3::new(0.0, 0.0, 0.0), Vec3::new(64.0, 16.0, 8.0));
+        let face = BrushFace { side_idx: 1, winding: face_winding(&planes, 1), plane: planes[1].clone() };
+        let rect = face.bounding_rect();
+
+        assert!((face.area() - 64.0 * 16.0).abs() < 0.1);
+        assert!((rect.width * rect.height - 64.0 * 16.0).abs() < 0.1);
+        assert!(rect.center.distance(Vec3::new(32.0, 8.0, 0.0)) < 0.01);
+        assert!((rect.up - rect.right.cross(rect.normal)).length() < 0.001);
+    }
+
+    #[test]
+    fn test_split_solid_in_half() {
+        let template = Side { material: TARGET_MATERIAL.to_string(), ..Default::default() };
+        let sides = box_planes(Vec3::new(0.0, 0.0, 0.0), Vec3::new(256.0, 64.0, 8.0)).iter()
+            .map(|p| plane_side(&template, p.normal * -p.dist, p.normal, TARGET_MATERIAL))
+            .collect();
+        let solid = Solid { id: 1, sides, ..Default::default() };
+
+        let (left, right) = split_solid(&solid, Vec3::new(64.0, 32.0, 4.0), Vec3::new(1.0, 0.0, 0.0), "tools/toolsnodraw").unwrap();
+        let x_range = |solid: &Solid| solid_faces(solid).iter()
+            .flat_map(|face| face.winding.iter().map(|p| p[0]))
+            .fold((f32::MAX, f32::MIN), |(lo, hi), x| (lo.min(x), hi.max(x)));
+        let (lo, hi) = x_range(&left);
+        assert!(lo.abs() < 0.01 && (hi - 64.0).abs() < 0.01, "Got {}..{}", lo, hi);
+        let (lo, hi) = x_range(&right);
+        assert!((lo - 64.0).abs() < 0.01 && (hi - 256.0).abs() < 0.01, "Got {}..{}", lo, hi);
+
+        // The far end of each part is gone, the cap takes its place
+        assert_eq!(left.sides.len(), 6);
+        assert_eq!(left.sides.iter().filter(|s| s.material == "tools/toolsnodraw").count(), 1);
+        assert!(split_solid(&solid, Vec3::new(300.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), "tools/toolsnodraw").is_none());
+    }
+
+    #[test]
+    fn test_oriented_box_of_rotated_brush() {
+        // 128x32x64 box rotated by 45 degrees around Z
+        let (sin, cos) = 45.0_f32.to_radians().sin_cos();
+        let x = Vec3::new(cos, sin, 0.0);
+        let y = Vec3::new(-sin, cos, 0.0);
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let planes = vec![
+            Plane::new(x, -64.0), Plane::new(x * -1.0, -64.0),
+            Plane::new(y, -16.0), Plane::new(y * -1.0, -16.0),
+            Plane::new(z, -32.0), Plane::new(z * -1.0, -32.0),
+        ];
+        let points: Vec<Vec3> = (0..planes.len()).flat_map(|i| face_winding(&planes, i)).collect();
+
+        let obb = OrientedBox::fit(&points, [x, y, z]);
+        assert!((obb.size() - Vec3::new(128.0, 32.0, 64.0)).length() < 0.01);
+        assert!(obb.center.length() < 0.01);
+        assert!(obb.intersects_segment(Vec3::new(-100.0, 0.0, 0.0), Vec3::new(100.0, 0.0, 0.0)));
+        assert!(!obb.intersects_segment(Vec3::new(-100.0, 60.0, 0.0), Vec3::new(-20.0, 60.0, 0.0)));
+
+        // Quaternion of a 45 degree yaw
+        let q = obb.rotation_quat();
+        let half = 22.5_f32.to_radians();
+        assert!((q[2] - half.sin()).abs() < 0.001 && (q[3] - half.cos()).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_bounding_rect_follows_rotation() {
+        // 64x16 rectangle on the floor, rotated by 30 degrees around Z
+        let (sin, cos) = 30.0_f32.to_radians().sin_cos();
+        let right = Vec3::new(cos, sin, 0.0);
+        let up = Vec3::new(-sin, cos, 0.0);
+        let winding = vec![
+            Vec3::ZERO,
+            up * 16.0,
+            right * 64.0 + up * 16.0,
+            right * 64.0,
+        ];
+        let face = BrushFace { side_idx: 0, winding, plane: Plane::new(Vec3::new(0.0, 0.0, 1.0), 0.0) };
+        let rect = face.bounding_rect();
+
+        let (long, short) = (rect.width.max(rect.height), rect.width.min(rect.height));
+        assert!((long - 64.0).abs() < 0.01 && (short - 16.0).abs() < 0.01, "Got {}x{}", rect.width, rect.height);
+        let long_axis = if rect.width > rect.height { rect.right } else { rect.up };
+        assert!(long_axis.dot(right).abs() > 0.999);
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use vmf_forge::prelude::*;
+
+use crate::math::{self, Vec3};
+use crate::text::{format_plane_points, parse_plane_points};
+use super::visgroups::{self, VisgroupFilter};
+
+// Nested instances deeper than this are most likely an instance including itself
+const MAX_INSTANCE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FixupStyle {
+    Prefix,
+    Postfix,
+    None,
+}
+
+impl FixupStyle {
+    fn from_entity(ent: &Entity) -> Self {
+        match ent.get("fixup_style").map(|s| s.trim()) {
+            Some("1") => FixupStyle::Postfix,
+            Some("2") => FixupStyle::None,
+            _ => FixupStyle::Prefix,
+        }
+    }
+
+    fn apply(self, instance_name: &str, name: &str) -> String {
+        match self {
+            FixupStyle::Prefix => format!("{}-{}", instance_name, name),
+            FixupStyle::Postfix => format!("{}-{}", name, instance_name),
+            FixupStyle::None => name.to_string(),
+        }
+    }
+}
+
+/// Placement of an instance: local space -> parent space
+#[derive(Debug, Clone, Copy)]
+struct InstanceTransform {
+    origin: Vec3,
+    basis: [Vec3; 3],
+}
+
+impl InstanceTransform {
+    fn from_entity(ent: &Entity) -> Self {
+        let origin = ent.get("origin").map(|s| Vec3::parse(s)).unwrap_or(Vec3::ZERO);
+        let angles = ent.get("angles").map(|s| Vec3::parse(s)).unwrap_or(Vec3::ZERO);
+        Self { origin, basis: math::angles_to_basis(angles) }
+    }
+
+    fn point(&self, p: Vec3) -> Vec3 {
+        math::rotate(&self.basis, p) + self.origin
+    }
+
+    fn vector(&self, v: Vec3) -> Vec3 {
+        math::rotate(&self.basis, v)
+    }
+
+    fn angles(&self, angles: Vec3) -> Vec3 {
+        let local = math::angles_to_basis(angles);
+        math::basis_to_angles(local.map(|axis| self.vector(axis)))
+    }
+}
+
+/// `$variable` and `#material` replacements of a single func_instance
+#[derive(Debug, Default)]
+struct Replacements {
+    variables: Vec<(String, String)>,
+    materials: HashMap<String, String>,
+}
+
+impl Replacements {
+    /// Reads `replaceNN` keys of the instance, with defaults from `func_instance_parms` of the instance file
+    fn collect(instance: &Entity, child: &VmfFile) -> Self {
+        let mut variables: HashMap<String, String> = HashMap::new();
+
+        for parms in child.entities.iter().filter(|e| is_class(e, "func_instance_parms")) {
+            for (key, value) in parms.key_values.iter().filter(|(k, _)| k.starts_with("parm")) {
+                // "$variable type default"
+                let mut parts = value.splitn(3, char::is_whitespace);
+                if let (Some(var), Some(_), Some(default)) = (parts.next(), parts.next(), parts.next()) {
+                    debug!("Instance parm {}: default {} = '{}'", key, var, default);
+                    variables.insert(var.to_string(), default.trim().to_string());
+                }
+            }
+        }
+
+        let mut materials = HashMap::new();
+        for (key, value) in instance.key_values.iter().filter(|(k, _)| k.starts_with("replace")) {
+            let Some((var, replacement)) = value.trim().split_once(char::is_whitespace) else { continue };
+            let replacement = replacement.trim().to_string();
+            if let Some(material) = var.strip_prefix('#') {
+                materials.insert(material.to_lowercase(), replacement);
+            } else if var.starts_with('$') {
+                variables.insert(var.to_string(), replacement);
+            } else {
+                warn!("Instance {:?}: unknown replacement '{}' in {}", instance.targetname(), value, key);
+            }
+        }
+
+        // Longest names first, so '$color' isn't eaten by '$col'
+        let mut variables: Vec<_> = variables.into_iter().collect();
+        variables.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+
+        Self { variables, materials }
+    }
+
+    fn apply(&self, vmf: &mut VmfFile) {
+        if !self.variables.is_empty() {
+            for ent in vmf.entities.iter_mut() {
+                for value in ent.key_values.values_mut() {
+                    self.replace_variables(value);
+                }
+                for (_, value) in ent.connections.iter_mut().flatten() {
+                    self.replace_variables(value);
+                }
+            }
+        }
+
+        if !self.materials.is_empty() {
+            for solid in all_solids_mut(vmf) {
+                for side in solid.sides.iter_mut() {
+                    if let Some(material) = self.materials.get(&side.material.to_lowercase()) {
+                        side.material = material.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    fn replace_variables(&self, value: &mut String) {
+        if !value.contains('$') {
+            return;
+        }
+        for (var, replacement) in &self.variables {
+            if value.contains(var.as_str()) {
+                *value = value.replace(var.as_str(), replacement);
+            }
+        }
+    }
+}
+
+struct InstanceExpander<'a> {
+    filter: &'a VisgroupFilter,
+    map_dir: PathBuf,
+    game_maps_dir: PathBuf,
+    cache: HashMap<PathBuf, Option<VmfFile>>,
+    next_id: u64,
+    auto_names: usize,
+    expanded: usize,
+}
+
+/// Collapses every `func_instance` into the map, like VBSP does before compiling.
+/// Lights, blockers and surfaces inside instances then look like regular map entities.
+/// Hidden objects of instance files are skipped using the same visgroup `filter`.
+/// Returns the number of expanded instances (nested ones included)
+pub fn expand_instances(vmf: &mut VmfFile, map_path: &Path, game_dir: &Path, filter: &VisgroupFilter) -> usize {
+    if !vmf.entities.iter().any(|e| is_class(e, "func_instance")) {
+        return 0;
+    }
+
+    let map_dir = map_path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut expander = InstanceExpander {
+        filter,
+        game_maps_dir: game_dir.join("maps"),
+        cache: HashMap::new(),
+        next_id: max_id(vmf) + 1,
+        auto_names: 0,
+        expanded: 0,
+        map_dir: map_dir.clone(),
+    };
+
+    expander.expand(vmf, &map_dir, 0);
+    info!("Expanded {} func_instance entities.", expander.expanded);
+    expander.expanded
+}
+
+impl InstanceExpander<'_> {
+    fn expand(&mut self, vmf: &mut VmfFile, file_dir: &Path, depth: usize) {
+        let (instances, rest): (Vec<_>, Vec<_>) = vmf.entities
+            .drain(..)
+            .partition(|e| is_class(e, "func_instance"));
+        vmf.entities.0 = rest;
+
+        // instance name -> fixup style, to redirect 'instance:' inputs later
+        let mut instance_names: HashMap<String, (String, FixupStyle)> = HashMap::new();
+
+        for instance in instances {
+            let Some(file) = instance.get("file").filter(|f| !f.trim().is_empty()) else {
+                warn!("func_instance {} has no file. Skipping.", instance.id());
+                continue;
+            };
+            if depth >= MAX_INSTANCE_DEPTH {
+                warn!("func_instance {} ('{}'): nested deeper than {} levels. Skipping.", instance.id(), file, MAX_INSTANCE_DEPTH);
+                continue;
+            }
+            let Some(path) = self.resolve(file, file_dir) else {
+                warn!("func_instance {}: file '{}' not found. Skipping.", instance.id(), file);
+                continue;
+            };
+            let Some(mut child) = self.load(&path) else { continue };
+            visgroups::strip_hidden_nested(&mut child, self.filter);
+
+            let name = match instance.targetname().filter(|n| !n.is_empty()) {
+                Some(n) => n.to_string(),
+                None => {
+                    self.auto_names += 1;
+                    format!("AutoInstance{}", self.auto_names)
+                }
+            };
+            let style = FixupStyle::from_entity(&instance);
+            debug!("Expanding instance '{}' from {:?}", name, path);
+
+            Replacements::collect(&instance, &child).apply(&mut child);
+            child.entities.retain(|e| !is_class(e, "func_instance_parms"));
+
+            let child_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            self.expand(&mut child, &child_dir, depth + 1);
+
+            fixup_names(&mut child, &name, style);
+            transform_vmf(&mut child, &InstanceTransform::from_entity(&instance));
+            self.reassign_ids(&mut child);
+            inherit_visgroups(&mut child, &instance);
+
+            vmf.world.solids.extend(child.world.solids);
+            vmf.entities.0.extend(child.entities.0);
+            instance_names.insert(name.to_lowercase(), (name, style));
+            self.expanded += 1;
+        }
+
+        redirect_instance_inputs(vmf, &instance_names);
+    }
+
+    /// Instance paths are relative to the file containing them, the map, or the game `maps/` folder
+    fn resolve(&self, file: &str, file_dir: &Path) -> Option<PathBuf> {
+        let mut relative = PathBuf::from(file.trim().replace('\\', "/"));
+        if relative.extension().is_none() {
+            relative.set_extension("vmf");
+        }
+
+        [file_dir, &self.map_dir, &self.game_maps_dir]
+            .iter()
+            .map(|dir| dir.join(&relative))
+            .find(|p| p.is_file())
+    }
+
+    fn load(&mut self, path: &Path) -> Option<VmfFile> {
+        self.cache
+            .entry(path.to_path_buf())
+            .or_insert_with(|| match VmfFile::open(path) {
+                Ok(vmf) => Some(vmf),
+                Err(e) => {
+                    warn!("Failed to parse instance {:?}: {}", path, e);
+                    None
+                }
+            })
+            .clone()
+    }
+
+    // Instance files reuse the same IDs, but the connection registry and backpatching need unique ones
+    fn reassign_ids(&mut self, vmf: &mut VmfFile) {
+        for ent in vmf.entities.iter_mut() {
+            ent.set("id".to_string(), self.next_id.to_string());
+            self.next_id += 1;
+        }
+        for solid in all_solids_mut(vmf) {
+            solid.id = self.next_id;
+            self.next_id += 1;
+            for side in solid.sides.iter_mut() {
+                side.id = self.next_id as u32;
+                self.next_id += 1;
+            }
+        }
+    }
+}
+
+/// Puts the collapsed contents into the visgroups of the func_instance, so they are hidden together with it.
+/// Visgroups of the instance file mean nothing in the map, its hidden objects are already gone
+fn inherit_visgroups(vmf: &mut VmfFile, instance: &Entity) {
+    let keys = ["visgroupid", "visgroupshown", "visgroupautoshown"];
+    let editor_keys: Vec<(String, String)> = keys.iter()
+        .filter_map(|&key| instance.get(key).map(|value| (key.to_string(), value.clone())))
+        .collect();
+    let (visgroups, shown) = visgroups::entity_visgroups(instance);
+
+    for ent in vmf.entities.iter_mut() {
+        ent.key_values.retain(|key, _| !keys.contains(&key.to_lowercase().as_str()));
+        for (key, value) in &editor_keys {
+            ent.set(key.clone(), value.clone());
+        }
+        ent.hidden |= instance.hidden;
+    }
+    for solid in all_solids_mut(vmf) {
+        solid.editor.visgroup_id = visgroups.first().copied();
+        solid.editor.visgroup_shown = shown && !instance.hidden;
+        solid.editor.visgroup_auto_shown = true;
+    }
+}
+
+/// Renames everything named inside the instance and every reference to those names
+fn fixup_names(vmf: &mut VmfFile, instance_name: &str, style: FixupStyle) {
+    if style == FixupStyle::None {
+        return;
+    }
+
+    // '@' and '!' names are global / special and are never renamed
+    let local_names: HashSet<String> = vmf.entities.iter()
+        .filter_map(|e| e.targetname())
+        .filter(|n| !n.is_empty() && !n.starts_with(['@', '!']))
+        .map(str::to_lowercase)
+        .collect();
+    let fixup = |name: &str| -> Option<String> {
+        local_names.contains(&name.trim().to_lowercase()).then(|| style.apply(instance_name, name.trim()))
+    };
+
+    for ent in vmf.entities.iter_mut() {
+        for (key, value) in ent.key_values.iter_mut() {
+            let fixed = if is_light_rule_key(key) {
+                fixup_light_rules(value, &fixup)
+            } else if is_name_key(key) {
+                fixup(value)
+            } else {
+                None
+            };
+            if let Some(fixed) = fixed {
+                *value = fixed;
+            }
+        }
+
+        for (_, value) in ent.connections.iter_mut().flatten() {
+            let sep = connection_separator(value);
+            let mut parts: Vec<String> = value.split(sep).map(str::to_string).collect();
+            if let Some(fixed) = fixup(&parts[0]) {
+                parts[0] = fixed;
+                *value = parts.join(&sep.to_string());
+            }
+        }
+    }
+}
+
+// Keys holding entity names, like `target_destination` keys of the FGD. Only these get fixed up, same as VBSP.
+// Light blockers are referenced by name too
+fn is_name_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    matches!(key.as_str(), "targetname" | "target" | "parentname" | "filtername" | "damagefilter")
+        || key.ends_with("_target")
+        || key.starts_with("pbr_blocker_name")
+}
+
+fn is_light_rule_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    key.starts_with("force_light_") || key.starts_with("exclude_light_")
+}
+
+// Force / exclude rules are a `,` / `;` separated list. Only light names are fixed up, `class:` and `group:` items stay
+fn fixup_light_rules(value: &str, fixup: &impl Fn(&str) -> Option<String>) -> Option<String> {
+    let mut changed = false;
+    let fixed: String = value.split_inclusive([',', ';'])
+        .map(|piece| {
+            let item = piece.trim_end_matches([',', ';']);
+            let pattern = item.trim();
+            let fixed = match pattern.split_once(':') {
+                Some((prefix, name)) if prefix.eq_ignore_ascii_case("name") => fixup(name).map(|name| format!("{}:{}", prefix, name)),
+                Some(_) => None,
+                None => fixup(pattern),
+            };
+            match fixed {
+                Some(fixed) => {
+                    changed = true;
+                    piece.replacen(pattern, &fixed, 1)
+                }
+                None => piece.to_string(),
+            }
+        })
+        .collect();
+
+    changed.then_some(fixed)
+}
+
+/// Rewrites `inst,instance:name;Input` outputs to the fixed-up entity inside the instance
+fn redirect_instance_inputs(vmf: &mut VmfFile, instance_names: &HashMap<String, (String, FixupStyle)>) {
+    if instance_names.is_empty() {
+        return;
+    }
+
+    for ent in vmf.entities.iter_mut() {
+        for (_, value) in ent.connections.iter_mut().flatten() {
+            let sep = connection_separator(value);
+            let mut parts: Vec<String> = value.split(sep).map(str::to_string).collect();
+            if parts.len() < 2 {
+                continue;
+            }
+
+            let Some((name, style)) = instance_names.get(&parts[0].trim().to_lowercase()) else { continue };
+            let Some((target, input)) = parts[1].trim()
+                .strip_prefix("instance:")
+                .and_then(|s| s.split_once(';'))
+            else { continue };
+
+            let target = if target.starts_with(['@', '!']) { target.to_string() } else { style.apply(name, target) };
+            let input = input.to_string();
+            parts[0] = target;
+            parts[1] = input;
+            *value = parts.join(&sep.to_string());
+        }
+    }
+}
+
+fn transform_vmf(vmf: &mut VmfFile, t: &InstanceTransform) {
+    for solid in all_solids_mut(vmf) {
+        transform_solid(solid, t);
+    }
+
+    for ent in vmf.entities.iter_mut() {
+        if let Some(origin) = ent.get("origin") {
+            let origin = t.point(Vec3::parse(origin));
+            ent.set("origin".to_string(), origin.to_origin());
+        }
+
+        // 'pitch' of lights overrides the angles pitch (with the opposite sign), keep both in sync
+        let pitch = ent.get("pitch").and_then(|s| s.parse::<f32>().ok());
+        if ent.get("angles").is_some() || pitch.is_some() {
+            let mut angles = ent.get("angles").map(|s| Vec3::parse(s)).unwrap_or(Vec3::ZERO);
+            if let Some(p) = pitch {
+                angles[0] = -p;
+            }
+
+            let angles = t.angles(angles);
+            ent.set("angles".to_string(), angles.to_origin());
+            if pitch.is_some() {
+                ent.set("pitch".to_string(), format!("{:.3}", -angles[0]));
+            }
+        }
+    }
+}
+
+fn transform_solid(solid: &mut Solid, t: &InstanceTransform) {
+    for side in solid.sides.iter_mut() {
+        if let Some(points) = parse_plane_points(&side.plane) {
+            side.plane = format_plane_points(points.map(|p| t.point(p)));
+        }
+        side.u_axis = transform_uv_axis(&side.u_axis, t);
+        side.v_axis = transform_uv_axis(&side.v_axis, t);
+
+        let Some(info) = side.dispinfo.as_mut() else { continue };
+        let start = t.point(Vec3::parse(&info.start_position.replace(['[', ']'], "")));
+        info.start_position = format!("[{}]", start.to_origin());
+        rotate_rows(&mut info.normals.rows, t);
+        if let Some(offsets) = info.offsets.as_mut() {
+            rotate_rows(&mut offsets.rows, t);
+        }
+        if let Some(offset_normals) = info.offset_normals.as_mut() {
+            rotate_rows(&mut offset_normals.rows, t);
+        }
+    }
+}
+
+/// Rotates the texture axis and moves the shift, so textures stay locked to the brush
+fn transform_uv_axis(axis_str: &str, t: &InstanceTransform) -> String {
+    // "[x y z shift] scale"
+    let Some((inner, scale)) = axis_str.trim().trim_start_matches('[').split_once(']') else {
+        return axis_str.to_string();
+    };
+    let values: Vec<f32> = inner.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+    let Ok(scale) = scale.trim().parse::<f32>() else { return axis_str.to_string() };
+    if values.len() != 4 || scale.abs() < 1e-6 {
+        return axis_str.to_string();
+    }
+
+    let axis = t.vector(Vec3::new(values[0], values[1], values[2]));
+    let shift = values[3] - axis.dot(t.origin) / scale;
+    format!("[{:.6} {:.6} {:.6} {:.4}] {}", axis[0], axis[1], axis[2], shift, scale)
+}
+
+fn rotate_rows(rows: &mut [String], t: &InstanceTransform) {
+    for row in rows.iter_mut() {
+        let values: Vec<f32> = row.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+        *row = values.chunks_exact(3)
+            .map(|c| {
+                let v = t.vector(Vec3::new(c[0], c[1], c[2]));
+                format!("{:.6} {:.6} {:.6}", v[0], v[1], v[2])
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+}
+
+fn connection_separator(value: &str) -> char {
+    if value.contains('\x1B') { '\x1B' } else { ',' }
+}
+
+fn is_class(ent: &Entity, class: &str) -> bool {
+    ent.classname().is_some_and(|c| c.eq_ignore_ascii_case(class))
+}
+
+fn all_solids_mut(vmf: &mut VmfFile) -> impl Iterator<Item = &mut Solid> {
+    let entity_solids = vmf.entities.iter_mut().filter_map(|e| e.solids.as_mut()).flatten();
+    vmf.world.solids.iter_mut().chain(entity_solids)
+}
+
+/// Highest entity, solid or side id in the map
+pub fn max_id(vmf: &VmfFile) -> u64 {
+    let entity_solids = vmf.entities.iter().filter_map(|e| e.solids.as_ref()).flatten();
+    let solid_ids = vmf.world.solids.iter()
+        .chain(entity_solids)
+        .flat_map(|s| std::iter::once(s.id).chain(s.sides.iter().map(|side| side.id as u64)));
+
+    vmf.entities.iter().map(Entity::id).chain(solid_ids).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(angles: &str, origin: &str) -> Entity {
+        let mut ent = Entity::new("func_instance", 1);
+        ent.set("targetname".to_string(), "door_1".to_string());
+        ent.set("angles".to_string(), angles.to_string());
+        ent.set("origin".to_string(), origin.to_string());
+        ent
+    }
+
+    fn assert_vec_eq(a: Vec3, b: Vec3) {
+        assert!(a.distance(b) < 0.01, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_transform_point_and_angles() {
+        let t = InstanceTransform::from_entity(&instance("0 90 0", "100 0 0"));
+        assert_vec_eq(t.point(Vec3::new(10.0, 0.0, 0.0)), Vec3::new(100.0, 10.0, 0.0));
+        let forward = math::angles_to_basis(t.angles(Vec3::new(0.0, 90.0, 0.0)))[0];
+        assert_vec_eq(forward, Vec3::new(-1.0, 0.0, 0.0));
+
+        // Spot light looking down stays down
+        let down = math::angles_to_basis(t.angles(Vec3::new(90.0, 0.0, 0.0)))[0];
+        assert_vec_eq(down, Vec3::new(0.0, 0.0, -1.0));
+
+        // Rolled and pitched angles survive the round trip
+        let basis = math::angles_to_basis(Vec3::new(-30.0, 45.0, 15.0));
+        let back = math::angles_to_basis(math::basis_to_angles(basis));
+        (0..3).for_each(|i| assert_vec_eq(basis[i], back[i]));
+    }
+
+    #[test]
+    fn test_uv_axis_stays_locked() {
+        let t = InstanceTransform::from_entity(&instance("0 90 0", "64 32 0"));
+        let local_point = Vec3::new(16.0, 8.0, 0.0);
+        let u = transform_uv_axis("[1 0 0 0] 0.25", &t);
+
+        // Texture coordinate of the same brush point must not change
+        let (inner, _) = u.trim_start_matches('[').split_once(']').unwrap();
+        let v: Vec<f32> = inner.split_whitespace().map(|v| v.parse().unwrap()).collect();
+        let world_u = t.point(local_point).dot(Vec3::new(v[0], v[1], v[2])) / 0.25 + v[3];
+        assert!((world_u - local_point[0] / 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_fixup_and_replacements() {
+        let mut light = Entity::new("light", 2);
+        light.set("targetname".to_string(), "lamp".to_string());
+        light.set("_light".to_string(), "$color 200".to_string());
+        light.set("message".to_string(), "lamp".to_string());
+
+        let mut button = Entity::new("func_button", 3);
+        button.set("targetname".to_string(), "@global_button".to_string());
+        button.connections = Some(vec![("OnPressed".to_string(), "lamp,TurnOn,,0,-1".to_string())]);
+        button.set("parentname".to_string(), "lamp".to_string());
+        button.set("pbr_blocker_name2".to_string(), "lamp".to_string());
+
+        let mut surface = Entity::new("func_ggx_surface", 4);
+        surface.set("exclude_light_1".to_string(), "class:lamp; lamp,name:lamp;group:lamp".to_string());
+        surface.set("force_light_1".to_string(), "other_lamp".to_string());
+
+        let mut child = VmfFile::default();
+        child.entities.0 = vec![light, button, surface];
+
+        let mut inst = instance("0 0 0", "0 0 0");
+        inst.set("replace01".to_string(), "$color 255 0 0".to_string());
+        Replacements::collect(&inst, &child).apply(&mut child);
+        fixup_names(&mut child, "door_1", FixupStyle::Prefix);
+
+        assert_eq!(child.entities[0].get("_light").unwrap(), "255 0 0 200");
+        assert_eq!(child.entities[0].targetname(), Some("door_1-lamp"));
+        assert_eq!(child.entities[0].get("message").unwrap(), "lamp");
+        assert_eq!(child.entities[1].targetname(), Some("@global_button"));
+        assert_eq!(child.entities[1].get("parentname").unwrap(), "door_1-lamp");
+        assert_eq!(child.entities[1].connections.as_ref().unwrap()[0].1, "door_1-lamp,TurnOn,,0,-1");
+        assert_eq!(child.entities[1].get("pbr_blocker_name2").unwrap(), "door_1-lamp");
+        assert_eq!(child.entities[2].get("exclude_light_1").unwrap(), "class:lamp; door_1-lamp,name:door_1-lamp;group:lamp");
+        assert_eq!(child.entities[2].get("force_light_1").unwrap(), "other_lamp");
+    }
+
+    #[test]
+    fn test_contents_hide_with_the_instance() {
+        let mut light = Entity::new("light", 2);
+        light.set("visgroupid".to_string(), "7".to_string());
+        let mut child = VmfFile::default();
+        child.entities.0 = vec![light];
+        child.world.solids = vec![Solid { id: 3, ..Default::default() }];
+
+        let mut inst = instance("0 0 0", "0 0 0");
+        inst.set("visgroupid".to_string(), "1".to_string());
+        inst.set("visgroupshown".to_string(), "0".to_string());
+        inherit_visgroups(&mut child, &inst);
+
+        let mut map = VmfFile::default();
+        map.visgroups.groups = vec![VisGroup { name: "Night".to_string(), id: 1, ..Default::default() }];
+        map.world.solids = child.world.solids;
+        map.entities.0 = child.entities.0;
+        assert_eq!(map.entities[0].get("visgroupid").unwrap(), "1");
+        assert_eq!(visgroups::strip_hidden(&mut map, &VisgroupFilter::default()), 2);
+    }
+
+    #[test]
+    fn test_redirect_instance_inputs() {
+        let mut relay = Entity::new("logic_relay", 5);
+        relay.connections = Some(vec![("OnTrigger".to_string(), "door_1,instance:lamp;TurnOff,,0.5,-1".to_string())]);
+
+        let mut vmf = VmfFile::default();
+        vmf.entities.0 = vec![relay];
+        let names = HashMap::from([("door_1".to_string(), ("door_1".to_string(), FixupStyle::Prefix))]);
+        redirect_instance_inputs(&mut vmf, &names);
+
+        assert_eq!(vmf.entities[0].connections.as_ref().unwrap()[0].1, "door_1-lamp,TurnOff,,0.5,-1");
+    }
+}
@@ -0,0 +1,149 @@
+use vmf_forge::prelude::Entity;
+
+use crate::text::{sanitize_name, wildcard_match};
+use crate::types::LightDef;
+
+/// What a rule pattern is matched against. Later variants are more specific and win conflicts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RuleTarget {
+    Class,
+    Group,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    Force,
+    Exclude,
+}
+
+/// One `force_light_*` / `exclude_light_*` pattern
+#[derive(Debug, Clone)]
+pub struct LightRule {
+    pub target: RuleTarget,
+    pub pattern: String,
+    pub action: RuleAction,
+}
+
+impl LightRule {
+    /// Parses `[name:|group:|class:]pattern`, plain patterns match light names
+    pub fn parse(value: &str, action: RuleAction) -> Option<Self> {
+        let value = value.trim();
+        let (target, pattern) = match value.split_once(':') {
+            Some((prefix, rest)) if prefix.eq_ignore_ascii_case("class") => (RuleTarget::Class, rest),
+            Some((prefix, rest)) if prefix.eq_ignore_ascii_case("group") => (RuleTarget::Group, rest),
+            Some((prefix, rest)) if prefix.eq_ignore_ascii_case("name") => (RuleTarget::Name, rest),
+            _ => (RuleTarget::Name, value),
+        };
+
+        let pattern = pattern.trim();
+        (!pattern.is_empty()).then(|| Self { target, pattern: pattern.to_string(), action })
+    }
+
+    pub fn matches(&self, light: &LightDef) -> bool {
+        match self.target {
+            RuleTarget::Class => wildcard_match(&self.pattern, &light.classname),
+            RuleTarget::Group => light.groups.iter().any(|group| wildcard_match(&self.pattern, group)),
+            // Old maps have sanitized names in the rules, so try both
+            RuleTarget::Name => !light.target_name.is_empty() && (
+                wildcard_match(&self.pattern, &light.target_name)
+                || wildcard_match(&sanitize_name(&self.pattern), &sanitize_name(&light.target_name))
+            ),
+        }
+    }
+
+    fn is_exact(&self) -> bool {
+        !self.pattern.contains(['*', '?'])
+    }
+}
+
+/// Parses a channel bitmask (`pbr_channels`, `pbr_receive_channels`), decimal or `0x` hex
+pub fn parse_channels(value: &str) -> Option<u32> {
+    let value = value.trim();
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Force / exclude rules of a surface
+#[derive(Debug, Clone, Default)]
+pub struct LightRules {
+    pub rules: Vec<LightRule>,
+}
+
+impl LightRules {
+    /// Reads every `force_light_*` and `exclude_light_*` key. One key can hold several patterns separated by `,` or `;`
+    pub fn from_entity(ent: &Entity) -> Self {
+        let mut rules = Vec::new();
+        for (key, value) in ent.key_values.iter() {
+            let key = key.to_lowercase();
+            let action = if key.starts_with("force_light_") {
+                RuleAction::Force
+            } else if key.starts_with("exclude_light_") {
+                RuleAction::Exclude
+            } else {
+                continue;
+            };
+
+            rules.extend(value.split([',', ';']).filter_map(|pattern| LightRule::parse(pattern, action)));
+        }
+
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The most specific matching rule wins: names over groups over classes, exact patterns over wildcards.
+    /// If force and exclude are still tied, exclude wins
+    pub fn verdict(&self, light: &LightDef) -> Option<RuleAction> {
+        self.rules.iter()
+            .filter(|rule| rule.matches(light))
+            .max_by_key(|rule| (rule.target, rule.is_exact(), rule.action == RuleAction::Exclude))
+            .map(|rule| rule.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Vec3;
+
+    fn light(target_name: &str, classname: &str, groups: &[&str]) -> LightDef {
+        LightDef {
+            target_name: target_name.to_string(),
+            is_named_light: !target_name.is_empty(),
+            classname: classname.to_string(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            ..LightDef::test_point("light_1", Vec3::ZERO)
+        }
+    }
+
+    #[test]
+    fn test_rule_priority() {
+        let mut ent = Entity::new("func_ggx_surface", 1);
+        ent.set("exclude_light_1".to_string(), "class:light_spot".to_string());
+        ent.set("force_light_1".to_string(), "group:stage".to_string());
+        ent.set("exclude_light_7".to_string(), "Hall_Lamp*; group:stage".to_string());
+        ent.set("force_light_12".to_string(), "hall_lamp_03".to_string());
+        let rules = LightRules::from_entity(&ent);
+        assert_eq!(rules.rules.len(), 5);
+
+        assert_eq!(rules.verdict(&light("", "light_spot", &[])), Some(RuleAction::Exclude));
+        assert_eq!(rules.verdict(&light("", "light", &[])), None);
+        // Group beats class, exclude beats force on a tie
+        assert_eq!(rules.verdict(&light("", "light_spot", &["stage"])), Some(RuleAction::Exclude));
+        assert_eq!(rules.verdict(&light("hall_lamp_01", "light", &[])), Some(RuleAction::Exclude));
+        // Exact name beats the wildcard
+        assert_eq!(rules.verdict(&light("HALL_LAMP_03", "light", &["stage"])), Some(RuleAction::Force));
+    }
+
+    #[test]
+    fn test_parse_channels() {
+        assert_eq!(parse_channels(" 6 "), Some(6));
+        assert_eq!(parse_channels("0x10"), Some(16));
+        assert_eq!(parse_channels("all"), None);
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use vmf_forge::prelude::*;
+
+use super::instances::{self, max_id};
+use super::visgroups::{self, VisgroupFilter};
+
+/// One VMF of a Hammer manifest
+#[derive(Debug)]
+pub struct Submap {
+    pub name: String,
+    /// Path as written in the manifest (relative to it)
+    pub file: String,
+    pub path: PathBuf,
+    pub internal_id: i32,
+    pub top_level: bool,
+    pub vmf: VmfFile,
+}
+
+/// Hammer map manifest (`.vmm`), a map split into several submap VMFs
+#[derive(Debug)]
+pub struct Manifest {
+    pub path: PathBuf,
+    pub submaps: Vec<Submap>,
+    source: String,
+    // Where merged solids / entities came from, by their (unique) ID
+    solid_owner: HashMap<u64, usize>,
+    entity_owner: HashMap<u64, usize>,
+}
+
+impl Manifest {
+    /// Loads the manifest and all of its submaps
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path).with_context(|| format!("Failed to read manifest {:?}", path))?;
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let mut submaps = Vec::new();
+        for block in parse_vmf_blocks(&source) {
+            let get = |key: &str| block.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str());
+            let Some(file) = get("File") else {
+                warn!("Manifest {:?}: submap without a file. Skipping.", path);
+                continue;
+            };
+
+            let sub_path = base_dir.join(file.replace('\\', "/"));
+            let mut file_handle = std::fs::File::open(&sub_path)
+                .with_context(|| format!("Failed to open submap {:?}", sub_path))?;
+            let vmf = VmfFile::parse_file(&mut file_handle)
+                .with_context(|| format!("Failed to parse submap {:?}", sub_path))?;
+
+            debug!("Loaded submap '{}' from {:?}", get("Name").unwrap_or(file), sub_path);
+            submaps.push(Submap {
+                name: get("Name").unwrap_or(file).to_string(),
+                file: file.to_string(),
+                path: sub_path,
+                internal_id: get("InternalID").and_then(|s| s.parse().ok()).unwrap_or(0),
+                top_level: get("TopLevel").is_some_and(|s| s.trim() == "1"),
+                vmf,
+            });
+        }
+
+        if submaps.is_empty() {
+            anyhow::bail!("Manifest {:?} has no submaps", path);
+        }
+        info!("Loaded manifest with {} submaps.", submaps.len());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            submaps,
+            source,
+            solid_owner: HashMap::new(),
+            entity_owner: HashMap::new(),
+        })
+    }
+
+    /// The submap new objects go to: the top-level one, like in Hammer
+    pub fn top_level(&self) -> usize {
+        self.submaps.iter().position(|s| s.top_level).unwrap_or(0)
+    }
+
+    /// Applies the visgroup overrides to every submap, each with its own visgroups
+    pub fn apply_visgroup_overrides(&mut self, filter: &VisgroupFilter) {
+        for submap in self.submaps.iter_mut() {
+            visgroups::apply_overrides(&mut submap.vmf, filter);
+        }
+    }
+
+    /// Collapses instances inside each submap, before `merge`, so their contents stay in the submap of the func_instance
+    pub fn expand_instances(&mut self, game_dir: &Path, filter: &VisgroupFilter) -> usize {
+        self.submaps.iter_mut()
+            .map(|submap| instances::expand_instances(&mut submap.vmf, &submap.path, game_dir, filter))
+            .sum()
+    }
+
+    /// Puts an entity created after `merge` into the submap of the entity `next_to`, instead of the top-level one
+    pub fn place_with(&mut self, id: u64, next_to: u64) {
+        if let Some(&owner) = self.entity_owner.get(&next_to) {
+            self.entity_owner.insert(id, owner);
+        }
+    }
+
+    /// Moves the world and entities of all submaps into one VMF. World settings come from the top-level submap.
+    /// Colliding IDs between submaps are renumbered, so every object can be traced back to its submap
+    pub fn merge(&mut self) -> VmfFile {
+        let top = self.top_level();
+        let mut merged = VmfFile {
+            versioninfo: self.submaps[top].vmf.versioninfo.clone(),
+            ..Default::default()
+        };
+        merged.world.key_values = self.submaps[top].vmf.world.key_values.clone();
+
+        let mut next_id = self.submaps.iter().map(|s| max_id(&s.vmf)).max().unwrap_or(0) + 1;
+        let mut used_solids: HashSet<u64> = HashSet::new();
+        let mut used_sides: HashSet<u64> = HashSet::new();
+        let mut used_entities: HashSet<u64> = HashSet::new();
+        let mut unique_id = |id: u64, used: &mut HashSet<u64>| -> u64 {
+            if used.insert(id) {
+                return id;
+            }
+            let new_id = next_id;
+            next_id += 1;
+            used.insert(new_id);
+            new_id
+        };
+
+        for (idx, submap) in self.submaps.iter_mut().enumerate() {
+            merged.visgroups.groups.extend(submap.vmf.visgroups.groups.iter().cloned());
+
+            for mut solid in submap.vmf.world.solids.drain(..) {
+                solid.id = unique_id(solid.id, &mut used_solids);
+                for side in solid.sides.iter_mut() {
+                    side.id = unique_id(side.id as u64, &mut used_sides) as u32;
+                }
+                self.solid_owner.insert(solid.id, idx);
+                merged.world.solids.push(solid);
+            }
+
+            for mut ent in submap.vmf.entities.drain(..) {
+                let id = unique_id(ent.id(), &mut used_entities);
+                if id != ent.id() {
+                    ent.set("id".to_string(), id.to_string());
+                }
+                for solid in ent.solids.iter_mut().flatten() {
+                    solid.id = unique_id(solid.id, &mut used_solids);
+                    for side in solid.sides.iter_mut() {
+                        side.id = unique_id(side.id as u64, &mut used_sides) as u32;
+                    }
+                }
+                self.entity_owner.insert(id, idx);
+                merged.entities.push(ent);
+            }
+        }
+
+        merged
+    }
+
+    /// Copy of the merged VMF without the hidden objects, each one judged by the visgroups of its own submap
+    pub fn visible_copy(&self, merged: &VmfFile, filter: &VisgroupFilter) -> VmfFile {
+        let mut parts: Vec<VmfFile> = self.submaps.iter()
+            .map(|submap| VmfFile { visgroups: submap.vmf.visgroups.clone(), ..Default::default() })
+            .collect();
+        for solid in &merged.world.solids {
+            parts[self.solid_owner(solid.id)].world.solids.push(solid.clone());
+        }
+        for ent in merged.entities.iter() {
+            parts[self.entity_owner(ent.id())].entities.push(ent.clone());
+        }
+
+        let mut visible = VmfFile {
+            versioninfo: merged.versioninfo.clone(),
+            visgroups: merged.visgroups.clone(),
+            ..Default::default()
+        };
+        visible.world.key_values = merged.world.key_values.clone();
+        for mut part in parts {
+            visgroups::strip_hidden_nested(&mut part, filter);
+            visible.world.solids.extend(part.world.solids);
+            visible.entities.0.extend(part.entities.0);
+        }
+        visible
+    }
+
+    /// Splits the merged VMF back into the submaps and saves them next to the originals with a `_pbr` suffix.
+    /// Objects created during the bake go to the top-level submap, unless placed with `place_with`.
+    /// The manifest is written to `output`, pointing to the new submaps relative to it
+    pub fn save_split(&mut self, merged: VmfFile, output: &Path) -> anyhow::Result<()> {
+        self.split(merged);
+
+        let output_dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let output_dir = output_dir.canonicalize().with_context(|| format!("Failed to open output folder {:?}", output_dir))?;
+        let mut manifest_text = self.source.clone();
+        for submap in &self.submaps {
+            let out_path = pbr_submap_path(&submap.path);
+            submap.vmf.save(&out_path)?;
+            debug!("Saved submap '{}' (ID {}) to {:?}", submap.name, submap.internal_id, out_path);
+
+            // Point the manifest to the new submap file
+            let new_file = relative_path(&out_path.canonicalize()?, &output_dir).to_string_lossy().to_string();
+            manifest_text = manifest_text.replace(&format!("\"{}\"", submap.file), &format!("\"{}\"", new_file));
+        }
+
+        std::fs::write(output, manifest_text).with_context(|| format!("Failed to write manifest {:?}", output))?;
+        Ok(())
+    }
+
+    // Gives every solid and entity back to the submap it came from
+    fn split(&mut self, merged: VmfFile) {
+        let VmfFile { world, entities, .. } = merged;
+
+        for solid in world.solids {
+            let owner = self.solid_owner(solid.id);
+            self.submaps[owner].vmf.world.solids.push(solid);
+        }
+        for ent in entities.0 {
+            let owner = self.entity_owner(ent.id());
+            self.submaps[owner].vmf.entities.push(ent);
+        }
+    }
+
+    fn solid_owner(&self, id: u64) -> usize {
+        self.solid_owner.get(&id).copied().unwrap_or_else(|| self.top_level())
+    }
+
+    fn entity_owner(&self, id: u64) -> usize {
+        self.entity_owner.get(&id).copied().unwrap_or_else(|| self.top_level())
+    }
+}
+
+fn pbr_submap_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{}_pbr.vmf", stem))
+}
+
+// `path` as seen from `base`. Both are canonical; on another drive it stays absolute
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let common = path.components().zip(base.components()).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return path.to_path_buf();
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+/// Collects key/values of every `VMF` block inside `Maps`. Keys repeat, so a plain KV map doesn't fit here
+fn parse_vmf_blocks(source: &str) -> Vec<Vec<(String, String)>> {
+    let tokens = tokenize(source);
+    let mut blocks = Vec::new();
+    let mut stack: Vec<String> = Vec::new();
+    let mut pending: Option<String> = None;
+
+    for token in tokens {
+        match token.as_str() {
+            "{" => {
+                stack.push(pending.take().unwrap_or_default().to_lowercase());
+                if stack.len() == 2 && stack[0] == "maps" && stack[1] == "vmf" {
+                    blocks.push(Vec::new());
+                }
+            }
+            "}" => {
+                stack.pop();
+                pending = None;
+            }
+            _ => match pending.take() {
+                None => pending = Some(token),
+                Some(key) => {
+                    if stack.len() == 2 && stack[0] == "maps" && stack[1] == "vmf"
+                        && let Some(block) = blocks.last_mut()
+                    {
+                        block.push((key, token));
+                    }
+                }
+            },
+        }
+    }
+
+    blocks
+}
+
+fn tokenize(source: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => tokens.push(chars.by_ref().take_while(|&c| c != '"').collect()),
+            '{' | '}' => tokens.push(c.to_string()),
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().find(|&c| c == '\n'); // Comment till the end of the line
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || matches!(next, '"' | '{' | '}') {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest_blocks() {
+        let source = r#"
+            versioninfo
+            {
+                "editorversion" "400"
+            }
+            Maps
+            {
+                VMF
+                {
+                    "Name" "main"
+                    "File" "test\main.vmf" // comment
+                    "InternalID" "0"
+                    "TopLevel" "1"
+                }
+                VMF
+                {
+                    "Name" "lights"
+                    "File" "test\lights.vmf"
+                    "InternalID" "1"
+                }
+            }
+        "#;
+
+        let blocks = parse_vmf_blocks(source);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0][1], ("File".to_string(), "test\\main.vmf".to_string()));
+        assert_eq!(blocks[1].len(), 3);
+    }
+
+    #[test]
+    fn test_merge_and_split_keep_owners() {
+        let submap = |name: &str, top_level: bool, ent_id: u64| {
+            let mut vmf = VmfFile::default();
+            vmf.world.solids = vec![Solid { id: 1, ..Default::default() }];
+            vmf.entities.0 = vec![Entity::new("light", ent_id)];
+            Submap {
+                name: name.to_string(),
+                file: format!("{}.vmf", name),
+                path: PathBuf::from(format!("{}.vmf", name)),
+                internal_id: 0,
+                top_level,
+                vmf,
+            }
+        };
+
+        let mut manifest = Manifest {
+            path: PathBuf::from("test.vmm"),
+            submaps: vec![submap("a", false, 5), submap("b", true, 5)],
+            source: String::new(),
+            solid_owner: HashMap::new(),
+            entity_owner: HashMap::new(),
+        };
+
+        let mut merged = manifest.merge();
+        assert_eq!(manifest.top_level(), 1);
+        assert_eq!(merged.world.solids.len(), 2);
+        assert_ne!(merged.world.solids[0].id, merged.world.solids[1].id);
+        assert_ne!(merged.entities[0].id(), merged.entities[1].id());
+
+        // Entity created by the bake goes to the top-level submap
+        merged.entities.push(Entity::new("material_modify_control", 100_000));
+        manifest.split(merged);
+
+        assert_eq!(manifest.submaps[0].vmf.entities.len(), 1);
+        assert_eq!(manifest.submaps[1].vmf.entities.len(), 2);
+        assert_eq!(manifest.submaps[0].vmf.world.solids.len(), 1);
+    }
+
+    #[test]
+    fn test_place_with_and_relative_paths() {
+        let mut manifest = Manifest {
+            path: PathBuf::from("test.vmm"),
+            submaps: Vec::new(),
+            source: String::new(),
+            solid_owner: HashMap::new(),
+            entity_owner: HashMap::from([(5, 0), (6, 1)]),
+        };
+        manifest.submaps = ["a", "b"].iter().map(|name| Submap {
+            name: name.to_string(),
+            file: format!("{}.vmf", name),
+            path: PathBuf::from(format!("{}.vmf", name)),
+            internal_id: 0,
+            top_level: *name == "b",
+            vmf: VmfFile::default(),
+        }).collect();
+
+        // A generated volume goes to the submap of its env_cubemap
+        let mut merged = VmfFile::default();
+        merged.entities.0 = vec![Entity::new("env_cubemap", 5), Entity::new("func_parallax_volume", 100)];
+        manifest.place_with(100, 5);
+        manifest.split(merged);
+        assert_eq!(manifest.submaps[0].vmf.entities.len(), 2);
+
+        let relative = relative_path(Path::new("/maps/level/a_pbr.vmf"), Path::new("/out"));
+        assert_eq!(relative, PathBuf::from("../maps/level/a_pbr.vmf"));
+    }
+}
@@ -1,6 +1,13 @@
+pub mod bvh;
 pub mod cubemaps;
+pub mod displacement;
 pub mod dynamic;
 pub mod geometry;
+pub mod instances;
+pub mod light_rules;
+pub mod manifest;
+pub mod proxies;
 pub mod scoring;
 pub mod surface_wrappers;
 pub mod tracer;
+pub mod visgroups;
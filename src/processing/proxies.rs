@@ -0,0 +1,247 @@
+use log::debug;
+
+use crate::math::{AABB, Vec3};
+use crate::types::{BlockerDef, LightDef, LightType};
+
+// Lights spread wider than this (in hammer units) never end up in one proxy
+const MAX_PROXY_SPREAD: f32 = 256.0;
+// Max per-channel difference of the normalized colors
+const PROXY_COLOR_TOLERANCE: f32 = 0.15;
+// Min dot product between directions of merged spots and area lights
+const PROXY_DIRECTION_TOLERANCE: f32 = 0.95;
+// Max distance between the planes of merged area lights
+const PROXY_PLANE_TOLERANCE: f32 = 16.0;
+
+struct Candidate {
+    light: LightDef,
+    score: f32,
+    extent: AABB,
+    members: Vec<String>,
+}
+
+impl Candidate {
+    fn is_mergeable(&self) -> bool {
+        self.score < f32::MAX && !self.light.is_named_light && !self.light.initially_dark
+    }
+}
+
+/// Merges nearby similar lights into proxy lights until no more than `max_count` are left, or nothing else can be merged.
+/// The closest pairs of the weakest lights go first. A proxy keeps the total intensity of its lights.
+/// Forced, named and initially dark lights are never merged, they need their own LUT slot
+pub fn merge_into_proxies(lights: Vec<(LightDef, f32)>, max_count: usize) -> Vec<(LightDef, f32)> {
+    let mut candidates: Vec<Candidate> = lights.into_iter()
+        .map(|(light, score)| {
+            let mut extent = AABB::new();
+            extent.extend(light.pos);
+            let members = vec![light.pbr_name.clone()];
+            Candidate { light, score, extent, members }
+        })
+        .collect();
+
+    while candidates.len() > max_count {
+        let mut best: Option<(f32, usize, usize)> = None;
+        for i in 0..candidates.len() {
+            for j in i + 1..candidates.len() {
+                let (a, b) = (&candidates[i], &candidates[j]);
+                if !a.is_mergeable() || !b.is_mergeable() || !can_merge(a, b) {
+                    continue;
+                }
+
+                let cost = a.light.pos.distance(b.light.pos) * (a.score + b.score);
+                if best.is_none_or(|(c, _, _)| cost < c) {
+                    best = Some((cost, i, j));
+                }
+            }
+        }
+
+        let Some((_, i, j)) = best else { break };
+        let b = candidates.remove(j);
+        let a = &mut candidates[i];
+        a.light = merge_lights(&a.light, &b.light);
+        a.score += b.score;
+        a.extent.extend(b.extent.min);
+        a.extent.extend(b.extent.max);
+        a.members.extend(b.members);
+    }
+
+    let mut merged: Vec<(LightDef, f32)> = candidates.into_iter()
+        .map(|mut c| {
+            if c.members.len() > 1 {
+                // Unique for the set of lights, so equal proxies of different surfaces compare equal
+                c.members.sort_unstable();
+                c.light.pbr_name = format!("proxy[{}]", c.members.join("+"));
+                debug!("  > Proxy light '{}' at {}", c.light.pbr_name, c.light.pos);
+            }
+            (c.light, c.score)
+        })
+        .collect();
+
+    merged.sort_by(|a, b| b.1.total_cmp(&a.1));
+    merged
+}
+
+/// Names of the lights merged into a proxy (empty for regular lights)
+pub fn proxy_members(pbr_name: &str) -> impl Iterator<Item = &str> {
+    pbr_name.strip_prefix("proxy[")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .into_iter()
+        .flat_map(|members| members.split('+'))
+}
+
+fn can_merge(a: &Candidate, b: &Candidate) -> bool {
+    let mut extent = a.extent;
+    extent.extend(b.extent.min);
+    extent.extend(b.extent.max);
+    if (extent.max - extent.min).length() > MAX_PROXY_SPREAD {
+        return false;
+    }
+
+    if a.light.channels != b.light.channels {
+        return false;
+    }
+
+    let (ca, cb) = (normalized_color(a.light.color), normalized_color(b.light.color));
+    if (0..3).any(|i| (ca[i] - cb[i]).abs() > PROXY_COLOR_TOLERANCE) {
+        return false;
+    }
+
+    let similar = |da: &Vec3, db: &Vec3| da.normalize().dot(db.normalize()) >= PROXY_DIRECTION_TOLERANCE;
+    match (&a.light.light_type, &b.light.light_type) {
+        (LightType::Point, LightType::Point) | (LightType::Sphere { .. }, LightType::Sphere { .. }) => true,
+        (LightType::Spot { direction: da, .. }, LightType::Spot { direction: db, .. }) => similar(da, db),
+        (
+            LightType::Rect { direction: da, bidirectional: ba, .. },
+            LightType::Rect { direction: db, bidirectional: bb, .. },
+        ) => {
+            ba == bb && similar(da, db) && da.normalize().dot(b.light.pos - a.light.pos).abs() <= PROXY_PLANE_TOLERANCE
+        }
+        _ => false,
+    }
+}
+
+fn normalized_color(color: Vec3) -> Vec3 {
+    let max = color[0].max(color[1]).max(color[2]);
+    if max > 0.0 { color / max } else { color }
+}
+
+// Energy weighted average of two lights. Area lights grow to cover both rectangles
+fn merge_lights(a: &LightDef, b: &LightDef) -> LightDef {
+    let total = (a.intensity + b.intensity).max(1e-6);
+    let (wa, wb) = (a.intensity / total, b.intensity / total);
+    let mix = |x: Vec3, y: Vec3| x * wa + y * wb;
+
+    let mut pos = mix(a.pos, b.pos);
+    let light_type = match (&a.light_type, &b.light_type) {
+        (LightType::Sphere { radius: ra }, LightType::Sphere { radius: rb }) => LightType::Sphere { radius: ra.max(*rb) },
+        (
+            LightType::Spot { direction: da, inner_angle: ia, outer_angle: oa, exponent: ea },
+            LightType::Spot { direction: db, inner_angle: ib, outer_angle: ob, exponent: eb },
+        ) => LightType::Spot {
+            direction: mix(*da, *db).normalize(),
+            inner_angle: ia.min(*ib),
+            outer_angle: oa.max(*ob),
+            exponent: ea * wa + eb * wb,
+        },
+        (
+            LightType::Rect { direction, right, width, height, bidirectional },
+            LightType::Rect { right: right_b, width: width_b, height: height_b, direction: direction_b, .. },
+        ) => {
+            let up = right.cross(*direction).normalize();
+            let up_b = right_b.cross(*direction_b).normalize();
+            let corners = |center: Vec3, r: Vec3, u: Vec3, w: f32, h: f32| {
+                [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)].map(|(x, y)| center + r * (w * x) + u * (h * y))
+            };
+
+            let (mut r_min, mut r_max, mut u_min, mut u_max) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+            let all = corners(a.pos, *right, up, *width, *height).into_iter()
+                .chain(corners(b.pos, *right_b, up_b, *width_b, *height_b));
+            for p in all {
+                let (r, u) = ((p - a.pos).dot(*right), (p - a.pos).dot(up));
+                r_min = r_min.min(r);
+                r_max = r_max.max(r);
+                u_min = u_min.min(u);
+                u_max = u_max.max(u);
+            }
+
+            // Keep the plane of the energy center, but the middle of the covered rectangle
+            let depth = (pos - a.pos).dot(*direction);
+            pos = a.pos + *right * ((r_min + r_max) * 0.5) + up * ((u_min + u_max) * 0.5) + *direction * depth;
+            LightType::Rect {
+                direction: *direction,
+                right: *right,
+                width: r_max - r_min,
+                height: u_max - u_min,
+                bidirectional: *bidirectional,
+            }
+        }
+        (light_type, _) => light_type.clone(),
+    };
+
+    // Range still has to reach everything both lights did
+    let range = (a.range + pos.distance(a.pos)).max(b.range + pos.distance(b.pos));
+
+    let mut groups = a.groups.clone();
+    groups.extend(b.groups.iter().filter(|group| !a.groups.contains(group)).cloned());
+
+    let mut blockers: Vec<BlockerDef> = a.blockers.clone();
+    blockers.extend(b.blockers.iter().filter(|blocker| !a.blockers.iter().any(|own| own.id == blocker.id)).cloned());
+
+    LightDef {
+        id: a.id,
+        target_name: String::new(),
+        pbr_name: a.pbr_name.clone(),
+        is_named_light: false,
+        classname: a.classname.clone(),
+        groups,
+        light_type,
+        pos,
+        color: mix(a.color, b.color),
+        intensity: a.intensity + b.intensity,
+        range,
+        attenuation_k: a.attenuation_k * wa + b.attenuation_k * wb,
+        fifty_percent_distance: a.fifty_percent_distance.zip(b.fifty_percent_distance).map(|(x, y)| x * wa + y * wb),
+        blockers,
+        initially_dark: false,
+        channels: a.channels | b.channels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(name: &str, pos: Vec3, intensity: f32) -> LightDef {
+        LightDef { intensity, range: 256.0, ..LightDef::test_point(name, pos) }
+    }
+
+    #[test]
+    fn test_row_of_lights_becomes_proxies() {
+        // 12 LEDs in a row, 32 units apart
+        let lights: Vec<(LightDef, f32)> = (0..12)
+            .map(|i| (point(&format!("led_{:02}", i), Vec3::new(i as f32 * 32.0, 0.0, 128.0), 10.0), 1.0))
+            .collect();
+
+        let merged = merge_into_proxies(lights, 8);
+        assert_eq!(merged.len(), 8);
+
+        // Energy is preserved, and the proxies still reach the lights' surroundings
+        let total: f32 = merged.iter().map(|(l, _)| l.intensity).sum();
+        assert!((total - 120.0).abs() < 0.001);
+        let proxy = merged.iter().find(|(l, _)| l.pbr_name.starts_with("proxy[")).unwrap();
+        assert!(proxy.0.range > 256.0);
+    }
+
+    #[test]
+    fn test_named_and_far_lights_stay_separate() {
+        let mut named = point("named", Vec3::ZERO, 10.0);
+        named.is_named_light = true;
+        let lights = vec![
+            (named, 1.0),
+            (point("near", Vec3::new(16.0, 0.0, 0.0), 10.0), 0.5),
+            (point("far", Vec3::new(1024.0, 0.0, 0.0), 10.0), 0.5),
+        ];
+
+        let merged = merge_into_proxies(lights, 1);
+        assert_eq!(merged.len(), 3);
+    }
+}
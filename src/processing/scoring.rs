@@ -1,144 +1,452 @@
-use crate::{constants::LUT_WIDTH, types::{LightDef, LightType}};
-use super::geometry::ConvexBrush;
+use crate::{constants::{LUT_MAX_BLOCKERS, LUT_WIDTH}, types::{BlockerDef, LightDef, LightType}};
+use super::geometry::CollisionWorld;
 use crate::math::{Vec3, AABB};
-use super::tracer;
+use crate::vmt_helper::VmtPbrParams;
+use super::light_rules::{LightRules, RuleAction};
+use super::{proxies, tracer};
 use log::debug;
-use std::collections::HashSet;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
 
 // Tolerance in degrees. Allows the light to "catch" an object if it extends slightly beyond the cone's boundaries.
 const CONE_ANGLE_TOLERANCE_DEG: f32 = 10.0;
 
+// Surfaces this far behind a one-sided area light still count, to be safe with faces touching the light
+const HALF_SPACE_EPSILON: f32 = 1.0;
+
+// MRAO textures aren't read during the bake, so BRDF scoring assumes these averages (scaled by the VMT params)
+const ASSUMED_ROUGHNESS: f32 = 0.5;
+const ASSUMED_METALNESS: f32 = 0.5;
+const MIN_ROUGHNESS: f32 = 0.05;
+
+// Above this many polygons, BRDF scoring only samples their centers
+const MAX_DETAILED_POLYGONS: usize = 16;
+
+/// How lights are rated against a surface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScoringMode {
+    /// Light falloff times the visible fraction of the surface
+    #[default]
+    Legacy,
+    /// Estimated diffuse + GGX specular response of the surface material
+    Brdf,
+}
+
+/// Material estimate used by the BRDF scoring
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceMaterial {
+    pub roughness: f32,
+    pub metalness: f32,
+    pub f0: f32,
+}
+
+impl Default for SurfaceMaterial {
+    fn default() -> Self {
+        Self::from_vmt(&VmtPbrParams::default(), None)
+    }
+}
+
+impl SurfaceMaterial {
+    pub fn from_vmt(params: &VmtPbrParams, roughness_mult: Option<f32>) -> Self {
+        let roughness = (ASSUMED_ROUGHNESS * params.roughness_bias * roughness_mult.unwrap_or(1.0)).clamp(MIN_ROUGHNESS, 1.0);
+        let metalness = (ASSUMED_METALNESS * params.metalness_scale).clamp(0.0, 1.0);
+        let f0 = params.dielectric_f0 + (1.0 - params.dielectric_f0) * metalness;
+
+        Self { roughness, metalness, f0 }
+    }
+
+    /// Diffuse + GGX specular response (times N.L) to light from direction `l`.
+    /// The viewer is assumed to look at the surface head-on, along its normal `n`
+    pub fn response(&self, n: Vec3, l: Vec3) -> f32 {
+        let n_dot_l = n.dot(l);
+        if n_dot_l <= 0.0 {
+            return 0.0;
+        }
+
+        let h = (l + n).normalize();
+        let n_dot_h = n.dot(h).max(0.0);
+        let v_dot_h = n_dot_h; // V == N
+
+        let alpha = self.roughness * self.roughness;
+        let alpha_sq = alpha * alpha;
+        let denom = n_dot_h * n_dot_h * (alpha_sq - 1.0) + 1.0;
+        let distribution = alpha_sq / (PI * denom * denom);
+
+        // Schlick-GGX, N.V is 1 for the head-on viewer
+        let k = (self.roughness + 1.0).powi(2) / 8.0;
+        let geometry = n_dot_l / (n_dot_l * (1.0 - k) + k);
+        let fresnel = self.f0 + (1.0 - self.f0) * (1.0 - v_dot_h).powi(5);
+
+        let specular = distribution * fresnel * geometry / (4.0 * n_dot_l);
+        let diffuse = (1.0 - self.metalness) * (1.0 - self.f0) / PI;
+
+        (diffuse + specular) * n_dot_l
+    }
+}
+
+/// Everything scoring needs to know about a surface (or a cluster of its solids)
+#[derive(Debug, Clone, Copy)]
+pub struct ScoringSurface<'a> {
+    pub bounds: &'a AABB,
+    pub polygons: &'a [Vec<Vec3>],
+    pub normal: Vec3,
+    pub material: SurfaceMaterial,
+    pub mode: ScoringMode,
+}
+
+
+/// Per-surface settings of the light selection
+#[derive(Debug, Clone, Copy)]
+pub struct SelectionRules<'a> {
+    pub light_rules: &'a LightRules,
+    // Lighting channels the surface receives, lights outside of them are skipped
+    pub receive_channels: u32,
+    pub min_score: f32,
+    pub proxy_lights: bool,
+    // Collect a `LightExplanation` for every light (`--explain`)
+    pub explain: bool,
+}
+
+/// Lights picked for a surface, and the ones that didn't make it
+#[derive(Debug, Default)]
+pub struct LightSelection {
+    pub selected: Vec<(LightDef, f32)>,
+    pub rejected: Vec<(LightDef, f32)>,
+    pub explanations: Vec<LightExplanation>,
+}
+
+/// Why a light was picked for a surface or not
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreReason {
+    #[default]
+    Selected,
+    Forced,
+    Excluded,
+    ChannelMask,
+    DistanceCull,
+    ShapeCull,
+    BrightnessCull,
+    Occluded,
+    BelowMinScore,
+    OverBudget,
+    MergedIntoProxy,
+}
+
+/// What went into a light score. `calculate_score` fills it, the selection adds the final verdict
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScoreBreakdown {
+    pub reason: ScoreReason,
+    pub distance: f32,
+    pub attenuation: f32,
+    pub window: f32,
+    pub visible_samples: usize,
+    pub total_samples: usize,
+    // Before the normalization
+    pub score: f32,
+}
+
+/// One line of the `--explain` report
+#[derive(Debug, Clone, Serialize)]
+pub struct LightExplanation {
+    pub light: String,
+    pub target_name: String,
+    pub light_type: &'static str,
+    pub normalized_score: f32,
+    pub slot: Option<usize>,
+    pub proxy: Option<String>,
+    #[serde(flatten)]
+    pub breakdown: ScoreBreakdown,
+}
 
 /// Scoring & Light Selection
 pub fn select_and_score_lights(
     all_lights: &[LightDef],
-    bounds: &AABB,
-    world_brushes: &[ConvexBrush],
-    exclude_lights: &HashSet<String>,
-    force_lights: &HashSet<String>,
-    min_score: f32,
-) -> (Vec<(LightDef, f32)>, Vec<(LightDef, f32)>) {
+    surface: &ScoringSurface,
+    world_brushes: &CollisionWorld,
+    rules: &SelectionRules,
+) -> LightSelection {
     let mut scored_lights: Vec<(usize, f32)> = Vec::new();
+    let mut breakdowns: Vec<(usize, ScoreBreakdown)> = Vec::new();
+    let mut explain = |idx: usize, breakdown: ScoreBreakdown| {
+        if rules.explain {
+            breakdowns.push((idx, breakdown));
+        }
+    };
 
     for (idx, light) in all_lights.iter().enumerate() {
-        // Check Exclude
-        if light.is_named_light && exclude_lights.contains(&light.target_name) { // TODo: improve it! add additional fake-naming key
-            debug!("  > Light '{}' (id: {}) manually excluded.", light.target_name, light.id);
-            continue;
+        // Check force / exclude rules
+        match rules.light_rules.verdict(light) {
+            Some(RuleAction::Exclude) => {
+                debug!("  > Light '{}' (id: {}) manually excluded.", light.target_name, light.id);
+                explain(idx, ScoreBreakdown { reason: ScoreReason::Excluded, ..Default::default() });
+                continue;
+            }
+            Some(RuleAction::Force) => {
+                debug!("  > Light '{}' (id: {}) manually included.", light.target_name, light.id);
+                scored_lights.push((idx, f32::MAX));
+                explain(idx, ScoreBreakdown { reason: ScoreReason::Forced, ..Default::default() });
+                continue;
+            }
+            None => {}
         }
 
-        // Check Force
-        if light.is_named_light && force_lights.contains(&light.target_name) { // TODo: improve it! add additional fake-naming key
-            debug!("  > Light '{}' (id: {}) manually included.", light.target_name, light.id);
-            scored_lights.push((idx, f32::MAX));
+        if light.channels & rules.receive_channels == 0 {
+            debug!("  > Light '{}' (id: {}) is not on the surface channels ({:#x} & {:#x}).", light.target_name, light.id, light.channels, rules.receive_channels);
+            explain(idx, ScoreBreakdown { reason: ScoreReason::ChannelMask, ..Default::default() });
             continue;
         }
 
-        let score = calculate_score(light, bounds, &world_brushes);
-        if score > 0.0 {
-            scored_lights.push((idx, score));
+        let breakdown = calculate_score(light, surface, world_brushes);
+        if breakdown.score > 0.0 {
+            scored_lights.push((idx, breakdown.score));
         }
+        explain(idx, breakdown);
     }
 
-    // Normalization of scores
-    let max_score = scored_lights.iter()
-        .filter(|(_, s)| *s < f32::MAX) // Ignore forced lights
+    // Normalization of scores. The sun has no falloff, so it's normalized by its own intensity, leaving
+    // sky visibility times response. Otherwise it would push every local light of a sunlit surface below min_score
+    let is_sun = |idx: usize| matches!(all_lights[idx].light_type, LightType::Directional { .. });
+    let max_local = scored_lights.iter()
+        .filter(|(idx, s)| *s < f32::MAX && !is_sun(*idx)) // Ignore forced lights
         .map(|(_, s)| *s)
         .fold(0.0, f32::max);
-    if max_score > 0.0 {
-        for (_, score) in scored_lights.iter_mut() {
-            if *score < f32::MAX {
-                *score /= max_score;
+    for (idx, score) in scored_lights.iter_mut() {
+        if *score >= f32::MAX {
+            continue;
+        }
+        let intensity = all_lights[*idx].intensity;
+        if is_sun(*idx) {
+            if intensity > 0.0 {
+                *score = (*score / intensity).min(1.0);
             }
+        } else if max_local > 0.0 {
+            *score /= max_local;
         }
     }
 
     // Sort lights by score in descending order
     scored_lights.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("NaN, its a bug"));
+    let normalized: HashMap<usize, f32> = scored_lights.iter().copied().collect();
+
+    let (accepted_candidates, rejected_candidates): (Vec<_>, Vec<_>) = scored_lights.into_iter()
+        .partition(|(_, s)| *s >= f32::MAX || *s >= rules.min_score);
+    let below_min: HashSet<usize> = rejected_candidates.iter().map(|(idx, _)| *idx).collect();
 
-    let (mut accepted_candidates, mut rejected_candidates): (Vec<_>, Vec<_>) = scored_lights.into_iter()
-        .partition(|(_, s)| *s >= f32::MAX || *s >= min_score);
+    let mut selected_lights: Vec<(LightDef, f32)> = accepted_candidates.into_iter()
+        .map(|(idx, score)| (all_lights[idx].clone(), score))
+        .collect();
+    let mut rejected_lights: Vec<(LightDef, f32)> = rejected_candidates.into_iter()
+        .map(|(idx, score)| (all_lights[idx].clone(), score))
+        .collect();
 
-    if accepted_candidates.len() > LUT_WIDTH {
-        let overflow = accepted_candidates.split_off(LUT_WIDTH);
-        rejected_candidates.extend(overflow);
+    // Merge the overflow into proxies first, so it still contributes
+    if rules.proxy_lights && selected_lights.len() > LUT_WIDTH {
+        selected_lights = proxies::merge_into_proxies(selected_lights, LUT_WIDTH);
+    }
+
+    if selected_lights.len() > LUT_WIDTH {
+        let overflow = selected_lights.split_off(LUT_WIDTH);
+        rejected_lights.extend(overflow);
     }
 
     // Stable sort to prefer named lights
-    accepted_candidates.sort_by_key(|(idx, _)| !all_lights[*idx].is_named_light);
+    selected_lights.sort_by_key(|(light, _)| !light.is_named_light);
 
-    let selected_lights: Vec<(LightDef, f32)> = accepted_candidates.into_iter()
-        .map(|(idx, score)| (all_lights[idx].clone(), score))
+    for (light, _) in selected_lights.iter_mut() {
+        light.blockers = select_blockers(light, surface.bounds);
+    }
+
+    let explanations = breakdowns.into_iter()
+        .map(|(idx, breakdown)| explain_light(&all_lights[idx], breakdown, normalized.get(&idx).copied(), below_min.contains(&idx), &selected_lights))
         .collect();
 
-    let rejected_lights: Vec<(LightDef, f32)> = rejected_candidates.into_iter()
-        .map(|(idx, score)| (all_lights[idx].clone(), score))
+    LightSelection { selected: selected_lights, rejected: rejected_lights, explanations }
+}
+
+// Final verdict for a light, after normalization, budget and proxies
+fn explain_light(light: &LightDef, mut breakdown: ScoreBreakdown, normalized: Option<f32>, below_min: bool, selected: &[(LightDef, f32)]) -> LightExplanation {
+    let slot = selected.iter().position(|(l, _)| l.pbr_name == light.pbr_name);
+    let proxy = selected.iter()
+        .find(|(l, _)| proxies::proxy_members(&l.pbr_name).any(|member| member == light.pbr_name))
+        .map(|(l, _)| l.pbr_name.clone());
+
+    if matches!(breakdown.reason, ScoreReason::Selected | ScoreReason::Forced) && slot.is_none() {
+        breakdown.reason = if proxy.is_some() {
+            ScoreReason::MergedIntoProxy
+        } else if below_min {
+            ScoreReason::BelowMinScore
+        } else {
+            ScoreReason::OverBudget
+        };
+    }
+
+    LightExplanation {
+        light: light.pbr_name.clone(),
+        target_name: light.target_name.clone(),
+        light_type: light.light_type.name(),
+        normalized_score: normalized.filter(|s| *s < f32::MAX).unwrap_or(0.0),
+        slot,
+        proxy,
+        breakdown,
+    }
+}
+
+// L1 spherical harmonics basis constants
+const SH_L0: f32 = 0.282_095;
+const SH_L1: f32 = 0.488_603;
+
+/// L1 spherical harmonics of the light the rejected lights would bring to `point` (coefficients L0, L1y, L1z, L1x, RGB each).
+/// Baked into the LUT as an ambient term, so surfaces don't go dark when lights are culled
+pub fn ambient_residual(rejected_lights: &[(LightDef, f32)], point: Vec3) -> [Vec3; 4] {
+    let mut sh = [Vec3::ZERO; 4];
+
+    for (light, _score) in rejected_lights {
+        let (dir, strength) = match light.light_type {
+            LightType::Directional { direction } => (direction.normalize() * -1.0, light.intensity),
+            _ => {
+                let to_light = light.pos - point;
+                let dist = (to_light.length() - light.emitter_radius()).max(0.0);
+                let dir = to_light.normalize();
+                (dir, light.intensity * falloff(light, dist) * emission_factor(light, dir * -1.0))
+            }
+        };
+        if strength <= 0.0 {
+            continue;
+        }
+
+        let radiance = light.color * strength;
+        sh[0] = sh[0] + radiance * SH_L0;
+        sh[1] = sh[1] + radiance * (SH_L1 * dir[1]);
+        sh[2] = sh[2] + radiance * (SH_L1 * dir[2]);
+        sh[3] = sh[3] + radiance * (SH_L1 * dir[0]);
+    }
+
+    sh
+}
+
+/// Picks the blockers that fit into the LUT, ranked by how many light-to-surface paths they cover.
+/// Blockers outside of the space between the light and the surface are dropped
+pub fn select_blockers(light: &LightDef, bounds: &AABB) -> Vec<BlockerDef> {
+    if light.blockers.is_empty() {
+        return Vec::new();
+    }
+
+    let emitters = light.emitter_points();
+    let sample_points = get_sample_points(bounds, light.pos);
+    let total_paths = (emitters.len() * sample_points.len()) as f32;
+
+    let mut path_bounds = *bounds;
+    emitters.iter().for_each(|&p| path_bounds.extend(p));
+
+    let mut ranked: Vec<(f32, f32, &BlockerDef)> = light.blockers.iter()
+        .filter_map(|blocker| {
+            let obb = blocker.oriented_box();
+            if !obb.bounds().intersects(&path_bounds) {
+                return None;
+            }
+
+            let covered = emitters.iter()
+                .flat_map(|e| sample_points.iter().map(move |p| (e, p)))
+                .filter(|(e, p)| obb.intersects_segment(**e, **p))
+                .count();
+
+            // Ties are broken by the distance to the light -> surface center line
+            let miss_dist = dist_point_segment(blocker.pos, light.pos, bounds.center);
+            Some((covered as f32 / total_paths, miss_dist, blocker))
+        })
         .collect();
 
-    (selected_lights, rejected_lights)
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.total_cmp(&b.1)));
+
+    if ranked.len() < light.blockers.len() || ranked.len() > LUT_MAX_BLOCKERS {
+        debug!("  > Light {} (id: {}): using {} of {} blockers", light.target_name, light.id, ranked.len().min(LUT_MAX_BLOCKERS), light.blockers.len());
+    }
+
+    ranked.into_iter()
+        .take(LUT_MAX_BLOCKERS)
+        .map(|(_, _, blocker)| blocker.clone())
+        .collect()
+}
+
+fn dist_point_segment(p: Vec3, a: Vec3, b: Vec3) -> f32 {
+    let ab = b - a;
+    let t = if ab.length_squared() > 0.0 { ((p - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0) } else { 0.0 };
+    p.distance(a + ab * t)
 }
 
 /// Calculates a "Score" for a (Light, Surface) pair.
 /// The higher the score, the more important the light is. 0.0 = light is not needed.
 pub fn calculate_score(
     light: &LightDef,
-    surface_aabb: &AABB,
-    world_brushes: &[ConvexBrush],
-) -> f32 {
-    let light_pos = light.pos;
+    surface: &ScoringSurface,
+    world_brushes: &CollisionWorld,
+) -> ScoreBreakdown {
+    let surface_aabb = surface.bounds;
     debug!("Calculating score for light {:?} (id: {}) on surface with center {:?}", light.target_name, light.id, surface_aabb.center);
+    let mut breakdown = ScoreBreakdown::default();
+
+    if let LightType::Directional { direction } = light.light_type {
+        breakdown.score = calculate_sun_score(light, direction, surface, world_brushes, &mut breakdown);
+        return breakdown;
+    }
+
+    // Quick distance test, from the closest part of the light shape
+    let emitters = light.emitter_points();
+    let closest_sq = emitters.iter()
+        .map(|p| crate::math::sq_dist_point_aabb(*p, surface_aabb))
+        .fold(f32::MAX, f32::min);
+    let dist = (closest_sq.sqrt() - light.emitter_radius()).max(0.0);
+    breakdown.distance = dist;
+    (breakdown.attenuation, breakdown.window) = falloff_terms(light, dist);
 
-    // Quick distance test
-    let dist_sq = crate::math::sq_dist_point_aabb(light_pos, surface_aabb);
-    let dist = dist_sq.sqrt();
     let max_dist = light.range * 2.0;
     if dist > max_dist {
         debug!("  > Culled by distance: dist={:.2} > max_dist={:.2}", dist, max_dist);
-        return 0.0;
+        breakdown.reason = ScoreReason::DistanceCull;
+        return breakdown;
     }
 
-    // Shape Check (Spot / Rect Direction)
-    // TODO: broken for now, FUCKING SHIT
-    // if !check_shape_visibility(light, surface_aabb) {
-    //     if light.is_named_light {
-    //          debug!("  > Named light '{}' (id: {}) culled by shape. (Closest Dist: {:.1})", light.target_name, light.id, dist);
-    //     } else {
-    //         debug!("  > Shape check failed for light '{}' (id: {}) on surface with center {:?}", light.target_name, light.id, surface_aabb.center);
-    //     }
-    //     return 0.0;
-    // }
-
-    // Scoring, using: 'I / (1 + K * d^2)'
-    let k = light.attenuation_k;
-    let attenuation = 1.0 / (1.0 + k * dist_sq);
+    // Shape Check (Spot cone / one-sided area lights)
+    if !check_shape_visibility(light, surface.polygons, surface_aabb) {
+        debug!("  > Culled by shape: light '{}' (id: {}) can't reach the surface (Closest Dist: {:.1})", light.target_name, light.id, dist);
+        breakdown.reason = ScoreReason::ShapeCull;
+        return breakdown;
+    }
 
-    // Windowing `(1 - (d^2 / r^2))^2`
-    let range_sq = light.range * light.range;
-    let dist_norm_sq = dist_sq / range_sq.max(0.001);
-    let window = (1.0 - dist_norm_sq).max(0.0);
-    let window_sq = window * window;
+    breakdown.score = match surface.mode {
+        ScoringMode::Legacy => calculate_legacy_score(light, dist, &emitters, surface_aabb, world_brushes, &mut breakdown),
+        ScoringMode::Brdf => calculate_brdf_score(light, &emitters, surface, world_brushes, &mut breakdown),
+    };
+    breakdown
+}
 
+fn calculate_legacy_score(light: &LightDef, dist: f32, emitters: &[Vec3], surface_aabb: &AABB, world_brushes: &CollisionWorld, breakdown: &mut ScoreBreakdown) -> f32 {
     // Estimated surface brightness (no way)
-    let estimated_brightness = light.intensity * attenuation * window_sq;
+    let estimated_brightness = light.intensity * falloff(light, dist);
     if estimated_brightness < 0.001 { // todo: think about it a bit more.. im not sure rn
         debug!("  > Culled by estimated_brightness ({} < 0.001)", estimated_brightness);
+        breakdown.reason = ScoreReason::BrightnessCull;
         return 0.0;
     }
 
     //  Raytracing (AABB corners + center)
-    let sample_points = get_sample_points(surface_aabb, light_pos);
+    let sample_points = get_sample_points(surface_aabb, light.pos);
     let mut visible_samples = 0;
 
     for point in &sample_points {
         // Check for occlusion: From the surface point TO the light
-        // If is_occluded returns false (no obstacle), we can see the light
-        if !tracer::is_occluded(*point, light_pos, world_brushes) {
+        // If is_occluded returns false (no obstacle), we can see the light. Any visible part of the shape counts
+        if emitters.iter().any(|emitter| !tracer::is_occluded(*point, *emitter, world_brushes)) {
             visible_samples += 1;
         }
     }
 
+    (breakdown.visible_samples, breakdown.total_samples) = (visible_samples, sample_points.len());
     if visible_samples == 0 {
         debug!("  > Culled by visibility: 0/{_total} samples visible", _total = sample_points.len());
+        breakdown.reason = ScoreReason::Occluded;
         return 0.0; // Fully occluded by walls
     }
 
@@ -154,52 +462,213 @@ pub fn calculate_score(
     score
 }
 
-/// Checks if point of AABB falls within the Spot cone or the front hemisphere of Rect
-fn check_shape_visibility(light: &LightDef, aabb: &AABB) -> bool {
-    let points = get_sample_points(aabb, light.pos);
+/// Averages the material response over samples of the surface polygons.
+/// Every visible emitter point adds its falloff, emission pattern and BRDF response
+fn calculate_brdf_score(light: &LightDef, emitters: &[Vec3], surface: &ScoringSurface, world_brushes: &CollisionWorld, breakdown: &mut ScoreBreakdown) -> f32 {
+    let sample_points = surface_sample_points(surface, light.pos);
+    let mut total = 0.0;
+    let mut visible_samples = 0;
+
+    for point in &sample_points {
+        let mut contribution = 0.0;
+        let mut visible = false;
+
+        for emitter in emitters {
+            if tracer::is_occluded(*point, *emitter, world_brushes) {
+                continue;
+            }
+            visible = true;
+
+            let to_light = *emitter - *point;
+            let dist = (to_light.length() - light.emitter_radius()).max(0.0);
+            let l = to_light.normalize();
+            contribution += falloff(light, dist) * emission_factor(light, l * -1.0) * surface.material.response(surface.normal, l);
+        }
+
+        if visible {
+            visible_samples += 1;
+            total += contribution / emitters.len() as f32;
+        }
+    }
+
+    (breakdown.visible_samples, breakdown.total_samples) = (visible_samples, sample_points.len());
+    if visible_samples == 0 {
+        debug!("  > Culled by visibility: 0/{} samples visible", sample_points.len());
+        breakdown.reason = ScoreReason::Occluded;
+        return 0.0;
+    }
+
+    let score = light.intensity * total / sample_points.len() as f32;
+    debug!("  > Light {} (id: {}, type: {}) | BRDF | Vis: {}/{} | Score: {:.4}",
+           light.target_name, light.id, light.light_type.name(), visible_samples, sample_points.len(), score);
+
+    score
+}
+
+/// Light falloff `I / (1 + K * d^2)`, windowed by `(1 - (d^2 / r^2))^2`
+fn falloff(light: &LightDef, dist: f32) -> f32 {
+    let (attenuation, window) = falloff_terms(light, dist);
+    attenuation * window
+}
+
+/// Attenuation and (squared) range window of the falloff
+fn falloff_terms(light: &LightDef, dist: f32) -> (f32, f32) {
+    let dist_sq = dist * dist;
+    let attenuation = 1.0 / (1.0 + light.attenuation_k * dist_sq);
+
+    let range_sq = light.range * light.range;
+    let dist_norm_sq = dist_sq / range_sq.max(0.001);
+    let window = (1.0 - dist_norm_sq).max(0.0);
+
+    (attenuation, window * window)
+}
 
+/// How much of the light goes out in direction `dir` (from the light to the surface)
+fn emission_factor(light: &LightDef, dir: Vec3) -> f32 {
     match &light.light_type {
-        LightType::Spot { direction, outer_angle, .. } => {
-            let light_dir = direction.normalize();
+        LightType::Spot { direction, inner_angle, outer_angle, .. } => {
+            // Angles are full opening angles, like in the shape check
+            let cos_outer = (outer_angle / 2.0).to_radians().cos();
+            let cos_inner = (inner_angle / 2.0).to_radians().cos().max(cos_outer + 1e-4);
+            ((direction.normalize().dot(dir) - cos_outer) / (cos_inner - cos_outer)).clamp(0.0, 1.0)
+        }
+        LightType::Rect { direction, bidirectional: true, .. } => direction.normalize().dot(dir).abs(),
+        LightType::Rect { direction, .. } | LightType::Disk { direction, .. } => direction.normalize().dot(dir).max(0.0),
+        _ => 1.0,
+    }
+}
 
+/// Score of a directional light: no falloff, only the fraction of samples that see the sky towards the sun.
+/// BRDF scoring also weights it by the material response to the sun direction. Not comparable to local lights,
+/// `select_and_score_lights` normalizes it separately
+fn calculate_sun_score(light: &LightDef, direction: Vec3, surface: &ScoringSurface, world_brushes: &CollisionWorld, breakdown: &mut ScoreBreakdown) -> f32 {
+    let to_sun = direction.normalize() * -1.0;
+    let sample_points = get_sample_points(surface.bounds, surface.bounds.center + to_sun * tracer::SKY_TRACE_DIST);
+
+    let visible_samples = sample_points.iter()
+        .filter(|point| tracer::is_sky_visible(**point, to_sun, world_brushes))
+        .count();
+
+    (breakdown.visible_samples, breakdown.total_samples) = (visible_samples, sample_points.len());
+    if visible_samples == 0 {
+        debug!("  > Culled by sky visibility: 0/{} samples see the sun", sample_points.len());
+        breakdown.reason = ScoreReason::Occluded;
+        return 0.0;
+    }
+
+    let visibility_factor = visible_samples as f32 / sample_points.len() as f32;
+    let response = match surface.mode {
+        ScoringMode::Legacy => 1.0,
+        ScoringMode::Brdf => surface.material.response(surface.normal, to_sun),
+    };
+    let score = light.intensity * visibility_factor * response;
+
+    debug!("  > Light {} (id: {}, type: {}) | Sky Vis: {:.2} | Score: {:.2}",
+           light.target_name, light.id, light.light_type.name(), visibility_factor, score);
+
+    score
+}
+
+/// Checks if any part of the surface falls within the Spot cone, or in front of a one-sided area light.
+/// Falls back to the AABB sample points when the surface has no polygons
+fn check_shape_visibility(light: &LightDef, polygons: &[Vec<Vec3>], aabb: &AABB) -> bool {
+    let fallback;
+    let polygons = if polygons.iter().any(|p| !p.is_empty()) {
+        polygons
+    } else {
+        fallback = vec![get_sample_points(aabb, light.pos)];
+        &fallback[..]
+    };
+
+    match &light.light_type {
+        LightType::Spot { direction, outer_angle, .. } => {
             // Expand the angle by the tolerance constant
             // outer_angle in Source is the full opening angle, so divide by 2
             let effective_angle_deg = (outer_angle / 2.0) + CONE_ANGLE_TOLERANCE_DEG;
+            if effective_angle_deg >= 180.0 {
+                return true;
+            }
+
+            let light_dir = direction.normalize();
             let limit_cos = effective_angle_deg.to_radians().cos();
+            polygons.iter().any(|polygon| polygon_min_cone_angle_cos(light.pos, light_dir, polygon) >= limit_cos)
+        },
+        LightType::Rect { direction, bidirectional: false, .. } | LightType::Disk { direction, .. } => {
+            // Anything in front of the emitter plane
+            let light_dir = direction.normalize();
+            polygons.iter().flatten().any(|p| (*p - light.pos).dot(light_dir) > -HALF_SPACE_EPSILON)
+        },
+        _ => true, // Point light shines everywhere
+    }
+}
 
-            for point in points.iter() {
-                let to_target = *point - light.pos;
-                let dist = to_target.dot(to_target).sqrt();
-                if dist < 0.1 { return true; }
+/// Cosine of the smallest angle between the cone axis and any point of the polygon, seen from the apex.
+/// Exact for convex polygons: the closest point is where the axis hits the face, or lies on an edge
+fn polygon_min_cone_angle_cos(apex: Vec3, axis: Vec3, polygon: &[Vec3]) -> f32 {
+    let dir_to = |p: Vec3| (p - apex).normalize();
+    if polygon.iter().any(|p| p.distance(apex) < 0.1) {
+        return 1.0;
+    }
 
-                let dir_to_target = Vec3::new(to_target.0 / dist, to_target.1 / dist, to_target.2 / dist);
-                let cos_angle = light_dir.dot(dir_to_target);
+    // Axis ray goes through the face
+    if polygon.len() >= 3 && ray_hits_polygon(apex, axis, polygon) {
+        return 1.0;
+    }
 
-                if cos_angle >= limit_cos {
-                    return true;
-                }
-            }
-            return false;
-        },
-        LightType::Rect { direction, bidirectional, .. } => {
-            if !bidirectional {
-                let light_dir = direction.normalize();
-                for point in points {
-                    let to_target = point - light.pos;
-                    let dist = to_target.dot(to_target).sqrt();
-                    if dist < 0.1 { return true; }
-
-                    let dir_to_target = Vec3::new(to_target.0 / dist, to_target.1 / dist, to_target.2 / dist);
-                    if light_dir.dot(dir_to_target) >= -0.1 {
-                        return true;
-                    }
-                }
-                return false;
-            }
-        },
-        _ => {} // Point light shines everywhere
+    let mut best = polygon.iter().map(|p| axis.dot(dir_to(*p))).fold(-1.0, f32::max);
+    if polygon.len() < 2 {
+        return best;
+    }
+
+    for i in 0..polygon.len() {
+        let a = dir_to(polygon[i]);
+        let b = dir_to(polygon[(i + 1) % polygon.len()]);
+
+        // Directions to the edge points form a great circle arc. Closest direction to the axis is
+        // the axis projected onto the arc plane, if it falls inside the arc
+        let n = a.cross(b);
+        if n.length_squared() < 1e-12 {
+            continue;
+        }
+        let n = n.normalize();
+        let projected = axis - n * axis.dot(n);
+        if projected.length_squared() < 1e-12 {
+            continue; // Axis is perpendicular to the arc plane, endpoints are the closest
+        }
+        let projected = projected.normalize();
+        if a.cross(projected).dot(n) >= 0.0 && projected.cross(b).dot(n) >= 0.0 {
+            best = best.max(axis.dot(projected));
+        }
+    }
+
+    best
+}
+
+fn ray_hits_polygon(origin: Vec3, dir: Vec3, polygon: &[Vec3]) -> bool {
+    (1..polygon.len() - 1).any(|i| {
+        let tri = [polygon[0], polygon[i], polygon[i + 1]];
+        super::displacement::intersect_triangle(origin, dir, &tri).is_some_and(|t| t >= 0.0)
+    })
+}
+
+/// Sample points on the surface polygons: centers plus slightly inset corners.
+/// Falls back to the AABB samples without polygons
+fn surface_sample_points(surface: &ScoringSurface, target_pos: Vec3) -> Vec<Vec3> {
+    let polygons: Vec<&Vec<Vec3>> = surface.polygons.iter().filter(|p| !p.is_empty()).collect();
+    if polygons.is_empty() {
+        return get_sample_points(surface.bounds, target_pos);
+    }
+
+    let detailed = polygons.len() <= MAX_DETAILED_POLYGONS;
+    let mut points = Vec::new();
+    for polygon in polygons {
+        let center = polygon.iter().fold(Vec3::ZERO, |acc, &p| acc + p) / polygon.len() as f32;
+        points.push(center);
+        if detailed {
+            points.extend(polygon.iter().map(|&p| p.lerp(center, 0.1)));
+        }
     }
-    true
+    points
 }
 
 /// gen point for raytrace-test. 8 corners + center + nearest
@@ -241,3 +710,228 @@ fn get_sample_points(aabb: &AABB, target_pos: Vec3) -> Vec<Vec3> {
 
     points
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_LIGHT_CHANNELS;
+    use crate::math::OrientedBox;
+    use crate::processing::geometry;
+
+    fn light_at(pos: Vec3, light_type: LightType) -> LightDef {
+        LightDef { light_type, ..LightDef::test_point("light_1", pos) }
+    }
+
+    fn spot(pos: Vec3, direction: Vec3, outer_angle: f32) -> LightDef {
+        light_at(pos, LightType::Spot { direction, inner_angle: outer_angle * 0.5, outer_angle, exponent: 1.0 })
+    }
+
+    // 256x256 floor quad at z=0
+    fn floor() -> (Vec<Vec<Vec3>>, AABB) {
+        let polygon = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 256.0, 0.0),
+            Vec3::new(256.0, 256.0, 0.0),
+            Vec3::new(256.0, 0.0, 0.0),
+        ];
+        let mut aabb = AABB::new();
+        polygon.iter().for_each(|&p| aabb.extend(p));
+        (vec![polygon], aabb)
+    }
+
+    #[test]
+    fn test_spot_cone_culling() {
+        let (polygons, aabb) = floor();
+        let down = Vec3::new(0.0, 0.0, -1.0);
+
+        // Straight down onto the middle, no vertex inside the narrow cone
+        assert!(check_shape_visibility(&spot(Vec3::new(128.0, 128.0, 512.0), down, 10.0), &polygons, &aabb));
+        // Pointing up, away from the floor
+        assert!(!check_shape_visibility(&spot(Vec3::new(128.0, 128.0, 64.0), Vec3::new(0.0, 0.0, 1.0), 60.0), &polygons, &aabb));
+
+        // Cone runs parallel to the floor and only grazes its far edge, all vertices are outside
+        let grazing = spot(Vec3::new(-128.0, 128.0, 32.0), Vec3::new(1.0, 0.0, 0.0), 10.0);
+        assert!(check_shape_visibility(&grazing, &polygons, &aabb));
+        // Same spot turned away from the floor
+        let away = spot(Vec3::new(-128.0, 128.0, 32.0), Vec3::new(-1.0, 0.0, 0.0), 10.0);
+        assert!(!check_shape_visibility(&away, &polygons, &aabb));
+    }
+
+    #[test]
+    fn test_one_sided_area_light_culling() {
+        let (polygons, aabb) = floor();
+        let rect = |direction: Vec3, bidirectional: bool| light_at(
+            Vec3::new(128.0, 128.0, 128.0),
+            LightType::Rect { direction, right: Vec3::new(1.0, 0.0, 0.0), width: 32.0, height: 32.0, bidirectional },
+        );
+
+        assert!(check_shape_visibility(&rect(Vec3::new(0.0, 0.0, -1.0), false), &polygons, &aabb));
+        assert!(!check_shape_visibility(&rect(Vec3::new(0.0, 0.0, 1.0), false), &polygons, &aabb));
+        assert!(check_shape_visibility(&rect(Vec3::new(0.0, 0.0, 1.0), true), &polygons, &aabb));
+
+        let disk = light_at(Vec3::new(128.0, 128.0, 128.0), LightType::Disk { direction: Vec3::new(0.0, 0.0, 1.0), radius: 8.0 });
+        assert!(!check_shape_visibility(&disk, &polygons, &aabb));
+    }
+
+    #[test]
+    fn test_brdf_prefers_lights_in_front_of_the_surface() {
+        let (polygons, aabb) = floor();
+        let world = CollisionWorld::new(Vec::new(), Vec::new());
+        let surface = |mode| ScoringSurface {
+            bounds: &aabb,
+            polygons: &polygons,
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            material: SurfaceMaterial { roughness: 0.3, metalness: 0.0, f0: 0.04 },
+            mode,
+        };
+
+        // Same distance to the floor, one above it and one almost in its plane
+        let above = light_at(Vec3::new(128.0, 128.0, 200.0), LightType::Point);
+        let grazing = light_at(Vec3::new(128.0, -200.0, 4.0), LightType::Point);
+
+        let brdf = surface(ScoringMode::Brdf);
+        assert!(calculate_score(&above, &brdf, &world).score > calculate_score(&grazing, &brdf, &world).score * 4.0);
+
+        // Lights behind the surface get nothing
+        let below = light_at(Vec3::new(128.0, 128.0, -200.0), LightType::Point);
+        assert_eq!(calculate_score(&below, &brdf, &world).score, 0.0);
+        assert!(calculate_score(&below, &surface(ScoringMode::Legacy), &world).score > 0.0);
+    }
+
+    #[test]
+    fn test_ambient_residual_points_to_the_lights() {
+        let center = Vec3::new(128.0, 128.0, 0.0);
+        let lights = vec![
+            (light_at(Vec3::new(128.0, 128.0, 128.0), LightType::Point), 0.05),
+            (light_at(Vec3::new(256.0, 128.0, 64.0), LightType::Point), 0.05),
+        ];
+
+        let sh = ambient_residual(&lights, center);
+        assert!(sh[0][0] > 0.0);
+        assert!(sh[2][0] > 0.0 && sh[3][0] > 0.0); // Lights are above and towards +X
+        assert!(sh[1][0].abs() < 1e-6);
+        assert_eq!(ambient_residual(&[], center), [Vec3::ZERO; 4]);
+    }
+
+    #[test]
+    fn test_selection_explains_every_light() {
+        let (polygons, aabb) = floor();
+        let world = CollisionWorld::new(Vec::new(), Vec::new());
+        let surface = ScoringSurface {
+            bounds: &aabb,
+            polygons: &polygons,
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            material: SurfaceMaterial::default(),
+            mode: ScoringMode::Legacy,
+        };
+
+        let named = |name: &str, pos: Vec3, intensity: f32| {
+            let mut light = light_at(pos, LightType::Point);
+            light.pbr_name = name.to_string();
+            light.intensity = intensity;
+            light
+        };
+        let lights = vec![
+            named("bright", Vec3::new(128.0, 128.0, 64.0), 100.0),
+            named("dim", Vec3::new(128.0, 128.0, 64.0), 1.0),
+            named("far", Vec3::new(128.0, 128.0, 5000.0), 100.0),
+            LightDef { channels: 0b10, ..named("screen", Vec3::new(128.0, 128.0, 64.0), 100.0) },
+        ];
+
+        let light_rules = LightRules::default();
+        let rules = SelectionRules {
+            light_rules: &light_rules,
+            receive_channels: DEFAULT_LIGHT_CHANNELS,
+            min_score: 0.1,
+            proxy_lights: true,
+            explain: true,
+        };
+        let selection = select_and_score_lights(&lights, &surface, &world, &rules);
+
+        let reasons: Vec<(&str, ScoreReason)> = selection.explanations.iter()
+            .map(|e| (e.light.as_str(), e.breakdown.reason))
+            .collect();
+        assert_eq!(reasons, vec![
+            ("bright", ScoreReason::Selected),
+            ("dim", ScoreReason::BelowMinScore),
+            ("far", ScoreReason::DistanceCull),
+            ("screen", ScoreReason::ChannelMask),
+        ]);
+        assert_eq!(selection.explanations[0].slot, Some(0));
+        assert_eq!(selection.explanations[1].breakdown.visible_samples, selection.explanations[1].breakdown.total_samples);
+    }
+
+    #[test]
+    fn test_sun_keeps_local_lights() {
+        let (polygons, aabb) = floor();
+        let mut sky_box = AABB::new();
+        sky_box.extend(Vec3::new(-1024.0, -1024.0, 2048.0));
+        sky_box.extend(Vec3::new(1024.0, 1024.0, 2064.0));
+        let sky = geometry::box_solid(&OrientedBox::from_aabb(&sky_box), "tools/toolsskybox", &mut 1);
+        let world = CollisionWorld::new(vec![geometry::ConvexBrush::from_vmf_solid(&sky).unwrap()], Vec::new());
+        let surface = ScoringSurface {
+            bounds: &aabb,
+            polygons: &polygons,
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            material: SurfaceMaterial::default(),
+            mode: ScoringMode::Legacy,
+        };
+
+        let mut sun = light_at(Vec3::ZERO, LightType::Directional { direction: Vec3::new(0.0, 0.0, -1.0) });
+        sun.intensity = 1000.0;
+        let lamp = light_at(Vec3::new(128.0, 128.0, 64.0), LightType::Point);
+
+        let light_rules = LightRules::default();
+        let rules = SelectionRules {
+            light_rules: &light_rules,
+            receive_channels: DEFAULT_LIGHT_CHANNELS,
+            min_score: 0.1,
+            proxy_lights: true,
+            explain: false,
+        };
+        let selection = select_and_score_lights(&[sun, lamp], &surface, &world, &rules);
+        assert_eq!(selection.selected.len(), 2);
+    }
+
+    #[test]
+    fn test_covered_surface_prefers_the_lamp() {
+        let (polygons, aabb) = floor();
+        let brush = |min: Vec3, max: Vec3, material: &str| {
+            let mut bounds = AABB::new();
+            bounds.extend(min);
+            bounds.extend(max);
+            let solid = geometry::box_solid(&OrientedBox::from_aabb(&bounds), material, &mut 1);
+            geometry::ConvexBrush::from_vmf_solid(&solid).unwrap()
+        };
+        // The roof leaves only a strip along the +X edge open to the sky
+        let world = CollisionWorld::new(vec![
+            brush(Vec3::new(-1024.0, -1024.0, 2048.0), Vec3::new(1024.0, 1024.0, 2064.0), "tools/toolsskybox"),
+            brush(Vec3::new(-1024.0, -1024.0, 128.0), Vec3::new(240.0, 1024.0, 144.0), "concrete/ceiling"),
+        ], Vec::new());
+        let surface = ScoringSurface {
+            bounds: &aabb,
+            polygons: &polygons,
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            material: SurfaceMaterial::default(),
+            mode: ScoringMode::Legacy,
+        };
+
+        let mut sun = light_at(Vec3::ZERO, LightType::Directional { direction: Vec3::new(0.0, 0.0, -1.0) });
+        sun.pbr_name = "sun".to_string();
+        sun.intensity = 1000.0;
+        let lamp = light_at(Vec3::new(128.0, 128.0, 64.0), LightType::Point);
+
+        let light_rules = LightRules::default();
+        let rules = SelectionRules {
+            light_rules: &light_rules,
+            receive_channels: DEFAULT_LIGHT_CHANNELS,
+            min_score: 0.1,
+            proxy_lights: true,
+            explain: false,
+        };
+        let selection = select_and_score_lights(&[sun, lamp], &surface, &world, &rules);
+        assert_eq!(selection.selected.len(), 2);
+        assert_eq!(selection.selected[0].0.pbr_name, "light_1");
+        assert!(selection.selected[1].1 < 0.5);
+    }
+}
@@ -1,20 +1,26 @@
-use crate::constants::TARGET_MATERIAL;
+use crate::constants::{DEFAULT_LIGHT_CHANNELS, TARGET_MATERIAL};
 use crate::math::{AABB, Vec3};
 use crate::types::{LightCluster, LightDef};
-use super::cubemaps::{self, ParallaxVolume};
-use super::geometry::{self, ConvexBrush};
-use super::scoring::select_and_score_lights;
-use crate::text::{calc_face_normal, parse_plane_points, sanitize_name};
+use super::cubemaps::{self, Cubemaps};
+use super::geometry::{self, CollisionWorld};
+use super::light_rules::{LightRules, parse_channels};
+use super::scoring::{ScoringMode, ScoringSurface, SelectionRules, SurfaceMaterial, ambient_residual, select_and_score_lights};
+use crate::text::{calc_face_normal, parse_plane_points};
 
 use derive_more::{Deref, DerefMut};
 use log::{debug, warn};
-use std::collections::HashSet;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, RwLock};
-use vmf_forge::prelude::{Entity, Solid};
+use vmf_forge::prelude::{Entity, Solid, VmfFile};
 
-pub const MAX_CUSTOM_SLOTS: usize = 4; // for force include/exclude
+pub const MAX_AUTO_SPLIT_DEPTH: u32 = 6; // up to 64 parts per solid
+
+// Faces shorter than this (in hammer units) along the long axis aren't split further
+const MIN_SPLIT_SIZE: f32 = 64.0;
+// Material of the cap sides created by splitting
+const SPLIT_CAP_MATERIAL: &str = "tools/toolsnodraw";
 
 const OVERRIDE_PARMS: [&str; 6] = [
     "ovr_roughness_mult",
@@ -38,10 +44,16 @@ pub struct GgxSurfaceEnt {
     pub bounding_box: AABB,
 
     // ggx_surface custom parms
-    pub exclude_lights: HashSet<String>,
-    pub force_lights: HashSet<String>,
+    pub light_rules: LightRules,
+    pub receive_channels: u32,
     pub min_score: f32,
     pub merge_solids: bool,
+    pub auto_split_depth: u32, // 0 = no auto split
+    // Set from the CLI and the template VMT before clustering
+    pub scoring_mode: ScoringMode,
+    pub material: SurfaceMaterial,
+    pub proxy_lights: bool,
+    pub explain: bool,
 
     pub template_material: String,
     pub override_roughness_mult: Option<f32>,
@@ -59,26 +71,49 @@ pub struct GgxSolid {
     pub solid: Solid,
     pub surface_normal: Vec3,
     pub surface_center: Vec3,
+    // Polygon of the target face, used for the light shape culling
+    pub surface_polygon: Vec<Vec3>,
     pub bound: AABB,
 }
 
-static SURFACE_COUNT: AtomicU32 = AtomicU32::new(0);
-
 impl GgxSurfaceEnt {
-    pub fn new(mut entity: Entity) -> Self {
-        let id = entity.id();
+    /// Splits `func_ggx_surface` entities to bake off the rest. Surfaces missing from `visible` (hidden in Hammer
+    /// or by the visgroup filter) stay with the rest, untouched
+    pub fn partition_visible(entities: Vec<Entity>, visible: &VmfFile) -> (Vec<Entity>, Vec<Entity>) {
+        let visible_ids: HashSet<u64> = visible.entities.iter().map(Entity::id).collect();
+        entities.into_iter().partition(|ent| {
+            ent.classname().unwrap_or("").eq_ignore_ascii_case("func_ggx_surface") && visible_ids.contains(&ent.id())
+        })
+    }
 
-        if entity.targetname().is_none() {
+    /// Builds all surfaces in parallel.
+    /// Unnamed surfaces (or ones with an empty name) are named up front in file order, so names don't depend on thread scheduling.
+    /// Generated names skip every targetname already used in the map (`other_entities` and the surfaces)
+    pub fn from_entities(mut entities: Vec<Entity>, other_entities: &[Entity]) -> Vec<Self> {
+        let mut taken: HashSet<String> = other_entities.iter()
+            .chain(entities.iter())
+            .filter_map(|ent| ent.targetname().map(str::to_lowercase))
+            .collect();
 
+        let mut idx = 0;
+        for entity in entities.iter_mut().filter(|ent| ent.targetname().is_none_or(|name| name.trim().is_empty())) {
+            let name = loop {
+                let name = format!("surface_{}", idx);
+                idx += 1;
+                if taken.insert(name.clone()) {
+                    break name;
+                }
+            };
+            entity.set("targetname".to_string(), name);
         }
-        let name = entity.targetname()
-            .map(String::from)
-            .unwrap_or_else(|| {
-                let idx = SURFACE_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                let new_name = format!("surface_{}", idx);
-                entity.set("targetname".to_string(), new_name.clone());
-                new_name
-            });
+
+        entities.into_par_iter().map(GgxSurfaceEnt::new).collect()
+    }
+
+    // Expects a named entity, see `from_entities`
+    fn new(mut entity: Entity) -> Self {
+        let id = entity.id();
+        let name = entity.targetname().unwrap_or_default().to_string();
 
         // Get surface origin. If not set, use AABB center
         let bounding_box = geometry::get_entity_aabb(&entity).unwrap_or(AABB::new());
@@ -93,22 +128,22 @@ impl GgxSurfaceEnt {
             panic!("Missing required key 'template_material' for func_ggx_surface");
         };
 
-        // Custom light filtering, processing exclude/force lights
-        let mut exclude_lights: HashSet<String> = HashSet::new();
-        let mut force_lights: HashSet<String> = HashSet::new();
-        for i in 1..=MAX_CUSTOM_SLOTS {
-            if let Some(name) = entity.get(&format!("exclude_light_{}", i))
-                && !name.is_empty() {
-                    exclude_lights.insert(sanitize_name(name));
-                }
-            if let Some(name) = entity.get(&format!("force_light_{}", i))
-                && !name.is_empty() {
-                    force_lights.insert(sanitize_name(name));
-                }
-        }
+        // Custom light filtering: any number of `exclude_light_*` / `force_light_*` keys
+        let light_rules = LightRules::from_entity(&entity);
+        let receive_channels = entity.get("pbr_receive_channels")
+            .and_then(|s| parse_channels(s))
+            .unwrap_or(DEFAULT_LIGHT_CHANNELS);
 
         let min_score = entity.get("min_score").and_then(|s| s.parse::<f32>().ok()).unwrap_or(0.10);
         let merge_solids = entity.get("merge_solids").map(|s| s != "0").unwrap_or(false);
+        let auto_split_depth = if entity.get("pbr_auto_split").is_some_and(|s| s != "0") {
+            entity.get("pbr_auto_split_depth")
+                .and_then(|s| s.parse::<u32>().ok())
+                .unwrap_or(3)
+                .min(MAX_AUTO_SPLIT_DEPTH)
+        } else {
+            0
+        };
 
         // TODO: use override parms
         let [
@@ -144,6 +179,11 @@ impl GgxSurfaceEnt {
             template_material,
             min_score,
             merge_solids,
+            auto_split_depth,
+            scoring_mode: ScoringMode::default(),
+            material: SurfaceMaterial::default(),
+            proxy_lights: true,
+            explain: false,
 
             override_roughness_mult,
             override_base_reflectivity,
@@ -152,8 +192,112 @@ impl GgxSurfaceEnt {
             override_fade_start,
             override_fade_end,
 
-            exclude_lights,
-            force_lights,
+            light_rules,
+            receive_channels,
+        }
+    }
+
+    /// Splits solids in halves along the long axis of their face, while the halves would select lights the whole solid can't.
+    /// The parts replace the original solid, so each of them gets its own cluster and material
+    pub fn auto_split(&mut self, all_lights: &[LightDef], world_brushes: &CollisionWorld) {
+        if self.auto_split_depth == 0 || self.merge_solids {
+            return;
+        }
+
+        let solids = std::mem::take(&mut self.ggx_solids);
+        for solid_arc in solids {
+            let solid = Arc::try_unwrap(solid_arc)
+                .map(|lock| lock.into_inner().unwrap())
+                .unwrap_or_else(|arc| GgxSolid::new(arc.read().unwrap().solid.clone()));
+
+            let mut parts = Vec::new();
+            self.split_recursive(solid, self.auto_split_depth, all_lights, world_brushes, &mut parts);
+            if parts.len() > 1 {
+                debug!("Surface '{}': solid {} was split into {} parts", self.name, parts[0].id, parts.len());
+            }
+            self.ggx_solids.extend(parts.into_iter().map(|part| Arc::new(RwLock::new(part))));
+        }
+    }
+
+    /// Split parts share the solid and side ids of their source solid, and the caps have id 0.
+    /// Gives every part after the first one and every cap fresh ids, so the saved VMF has no duplicates
+    pub fn renumber_split_solids(&self, next_id: &mut u64) {
+        let mut seen_solids = HashSet::new();
+        let mut seen_sides = HashSet::new();
+
+        for solid_arc in &self.ggx_solids {
+            let mut solid = solid_arc.write().unwrap();
+            if !seen_solids.insert(solid.id) {
+                solid.id = *next_id;
+                *next_id += 1;
+            }
+            for side in solid.sides.iter_mut() {
+                if side.id == 0 || !seen_sides.insert(side.id) {
+                    side.id = *next_id as u32;
+                    *next_id += 1;
+                }
+            }
+        }
+    }
+
+    fn split_recursive(&self, solid: GgxSolid, depth: u32, all_lights: &[LightDef], world_brushes: &CollisionWorld, out: &mut Vec<GgxSolid>) {
+        let halves = (depth > 0).then(|| self.split_in_half(&solid)).flatten();
+        let Some((front, back)) = halves else {
+            out.push(solid);
+            return;
+        };
+
+        // Worth it only if some part of the face loses a light it would have on its own
+        let whole = self.selected_light_names(&solid, all_lights, world_brushes);
+        let differs = [&front, &back].iter()
+            .any(|half| !self.selected_light_names(half, all_lights, world_brushes).is_subset(&whole));
+        if !differs {
+            out.push(solid);
+            return;
+        }
+
+        self.split_recursive(front, depth - 1, all_lights, world_brushes, out);
+        self.split_recursive(back, depth - 1, all_lights, world_brushes, out);
+    }
+
+    // Cuts the solid across the long axis of its target face
+    fn split_in_half(&self, solid: &GgxSolid) -> Option<(GgxSolid, GgxSolid)> {
+        let face = geometry::solid_faces(&solid.solid)
+            .into_iter()
+            .find(|face| solid.sides[face.side_idx].material.eq_ignore_ascii_case(TARGET_MATERIAL))?;
+        let rect = face.bounding_rect();
+
+        let (axis, length) = if rect.width >= rect.height { (rect.right, rect.width) } else { (rect.up, rect.height) };
+        if length < MIN_SPLIT_SIZE * 2.0 {
+            return None;
+        }
+
+        let (front, back) = geometry::split_solid(&solid.solid, rect.center, axis, SPLIT_CAP_MATERIAL)?;
+        Some((GgxSolid::new(front), GgxSolid::new(back)))
+    }
+
+    fn selected_light_names(&self, solid: &GgxSolid, all_lights: &[LightDef], world_brushes: &CollisionWorld) -> HashSet<String> {
+        let polygons = [solid.surface_polygon.clone()];
+        let surface = ScoringSurface {
+            bounds: &solid.bound,
+            polygons: &polygons,
+            normal: solid.surface_normal,
+            material: self.material,
+            mode: self.scoring_mode,
+        };
+
+        let rules = SelectionRules { explain: false, ..self.selection_rules() };
+        let selection = select_and_score_lights(all_lights, &surface, world_brushes, &rules);
+        selection.selected.into_iter().map(|(light, _)| light.pbr_name).collect()
+    }
+
+    pub fn selection_rules(&self) -> SelectionRules<'_> {
+        SelectionRules {
+            light_rules: &self.light_rules,
+            receive_channels: self.receive_channels,
+            min_score: self.min_score,
+            proxy_lights: self.proxy_lights,
+            explain: self.explain,
         }
     }
 
@@ -193,6 +337,12 @@ impl GgxSolid {
         // Center of face surface // todo: fix and use in UV-parent finder?
         let surface_center = (target_points[0] + target_points[1] + target_points[2]) / 3.0;
 
+        let surface_polygon = geometry::solid_faces(&solid)
+            .into_iter()
+            .find(|face| solid.sides[face.side_idx].material.eq_ignore_ascii_case(TARGET_MATERIAL))
+            .map(|face| face.winding)
+            .unwrap_or_default();
+
         // Calc bounding box
         let bounding_box = geometry::get_solid_aabb(&solid).unwrap_or(AABB::new());
 
@@ -200,6 +350,7 @@ impl GgxSolid {
             solid,
             surface_normal,
             surface_center,
+            surface_polygon,
             bound: bounding_box,
         }
     }
@@ -213,8 +364,8 @@ impl LightCluster {
         map_name: &str,
         game_dir: &Path,
         all_lights: &[LightDef],
-        world_brushes: &[ConvexBrush],
-        pcc_volumes: &[ParallaxVolume],
+        world_brushes: &CollisionWorld,
+        cubemaps: &Cubemaps,
     ) -> Vec<LightCluster> {
         let mat_base_rel = Path::new("maps").join(map_name);
         let mat_output_dir = game_dir.join("materials").join(&mat_base_rel);
@@ -222,15 +373,20 @@ impl LightCluster {
         debug!("Processing ggx_surface {:#?} id: {}", ggx_surface_name, ggx_surface.id);
         let build_cluster = |cluster_name: String, ggx_surface: &GgxSurfaceEnt, solids: Vec<Arc<RwLock<GgxSolid>>>, bound: AABB, normal: Vec3| -> LightCluster {
             let surface_material_path = mat_output_dir.join(&cluster_name);
+            let polygons: Vec<Vec<Vec3>> = solids.iter()
+                .map(|solid| solid.read().unwrap().surface_polygon.clone())
+                .collect();
 
-            let (selected_lights, rejected_lights) = select_and_score_lights(
-                all_lights,
-                &bound,
-                world_brushes,
-                &ggx_surface.exclude_lights,
-                &ggx_surface.force_lights,
-                ggx_surface.min_score
-            );
+            let surface = ScoringSurface {
+                bounds: &bound,
+                polygons: &polygons,
+                normal,
+                material: ggx_surface.material,
+                mode: ggx_surface.scoring_mode,
+            };
+
+            let selection = select_and_score_lights(all_lights, &surface, world_brushes, &ggx_surface.selection_rules());
+            let (selected_lights, rejected_lights) = (selection.selected, selection.rejected);
 
             if !selected_lights.is_empty() {
                 debug!("  -> Selected Lights: {:?}", selected_lights.iter().map(|(v, _)| &v.id).collect::<Vec<_>>());
@@ -241,11 +397,19 @@ impl LightCluster {
                 warn!("Surface '{}' (id: {}, pos: '{}') has no active lights.", cluster_name, ggx_surface.id, ggx_surface.origin);
             }
 
-            // == Match PCC Volume
-            let parallax_volume = cubemaps::find_parallax_volume(bound.center, normal, pcc_volumes);
-            let cubemap_name = parallax_volume.as_ref().map(|pcc| {
-                format!("maps/{}/c{}_{}_{}.hdr.vtf", map_name, pcc.cubemap_pos[0] as i32, pcc.cubemap_pos[1] as i32, pcc.cubemap_pos[2] as i32)
-            });
+            // == Match PCC Volume, or at least a plain cubemap
+            let side_ids: Vec<u32> = solids.iter()
+                .flat_map(|solid| solid.read().unwrap().sides.iter().map(|side| side.id).collect::<Vec<_>>())
+                .collect();
+            let (parallax_volume, pcc_blend) = match cubemaps::select_cubemap(&side_ids, &bound, normal, cubemaps, world_brushes) {
+                Some((pcc, blend)) => (Some(pcc), blend),
+                None => (None, None),
+            };
+            let cubemap_name = parallax_volume.as_ref().map(|pcc| cubemaps::cubemap_vtf_name(map_name, pcc.cubemap_pos));
+            let blend_cubemap_name = pcc_blend.as_ref().map(|(pcc, _)| cubemaps::cubemap_vtf_name(map_name, pcc.cubemap_pos));
+
+            // Whatever didn't make it into the LUT still lights the surface a bit
+            let ambient_sh = ambient_residual(&rejected_lights, bound.center);
 
             let mut initial_c4 =[1.0f32; 4];
             for (i, (light, _score)) in selected_lights.iter().take(4).enumerate() {
@@ -268,9 +432,13 @@ impl LightCluster {
                 lights: selected_lights,
                 initial_c4,
                 rejected_lights,
+                ambient_sh,
+                explanations: selection.explanations,
                 min_cluster_score: ggx_surface.min_score,
                 pcc_volume: parallax_volume,
                 cubemap_name,
+                pcc_blend,
+                blend_cubemap_name,
             }
         };
 
@@ -282,7 +450,7 @@ impl LightCluster {
 
             vec![build_cluster(cluster_name, ggx_surface, ggx_surface.ggx_solids.clone(), ggx_surface.bounding_box, normal)]
         } else {
-            ggx_surface.ggx_solids.iter().enumerate().map(|(i, solid_arc)| {
+            ggx_surface.ggx_solids.par_iter().enumerate().map(|(i, solid_arc)| {
                 let solid = solid_arc.read().unwrap();
                 let cluster_name = format!("{}_solid_{}", ggx_surface_name, i);
 
@@ -290,4 +458,203 @@ impl LightCluster {
             }).collect()
         }
     }
+
+    /// Merges clusters that would bake the same LUT: same template material, PCC volume and set of lights.
+    /// Solids of merged clusters use the material of the first one.
+    /// Clusters with named lights only merge inside their own ggx_surface, since `material_modify_control` works through the parent
+    pub fn merge_identical(clusters: Vec<LightCluster>) -> Vec<LightCluster> {
+        let mut merged: Vec<LightCluster> = Vec::with_capacity(clusters.len());
+        let mut groups: HashMap<String, usize> = HashMap::new();
+
+        for cluster in clusters {
+            let key = cluster.merge_key();
+            if let Some(&idx) = groups.get(&key) {
+                let target = &mut merged[idx];
+                debug!("Merging cluster '{}' into '{}'", cluster.name, target.name);
+                target.bound.extend(cluster.bound.min);
+                target.bound.extend(cluster.bound.max);
+                target.solids.extend(cluster.solids);
+                // Same selected lights, but not the same rejected ones. All of them make up the ambient
+                for (light, score) in cluster.rejected_lights {
+                    if !target.rejected_lights.iter().any(|(other, _)| other.pbr_name == light.pbr_name) {
+                        target.rejected_lights.push((light, score));
+                    }
+                }
+            } else {
+                groups.insert(key, merged.len());
+                merged.push(cluster);
+            }
+        }
+
+        // The ambient term is for the cluster center, which moved
+        for cluster in merged.iter_mut() {
+            cluster.ambient_sh = ambient_residual(&cluster.rejected_lights, cluster.bound.center);
+        }
+
+        merged
+    }
+
+    // Light order doesn't matter: the merged LUT keeps the order of the first cluster for all solids.
+    // Blockers are picked per cluster bound, so they're part of the key too
+    fn merge_key(&self) -> String {
+        let mut lights: Vec<String> = self.lights.iter()
+            .map(|(light, _)| {
+                let blockers: Vec<String> = light.blockers.iter().map(|b| b.id.to_string()).collect();
+                format!("{}[{}]", light.pbr_name, blockers.join(" "))
+            })
+            .collect();
+        lights.sort_unstable();
+
+        let has_named = self.lights.iter().any(|(light, _)| light.is_named_light);
+        let owner = if has_named { self.ggx_surface_id.to_string() } else { String::new() };
+
+        format!("{}|{:?}|{:?}|{}|{}", self.pbr_material.to_lowercase(), self.pcc_volume, self.pcc_blend, owner, lights.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::OrientedBox;
+    use crate::types::{BlockerDef, BlockerType};
+    use crate::processing::visgroups::{self, VisgroupFilter};
+    use vmf_forge::prelude::VisGroup;
+
+    fn light(name: &str, is_named_light: bool) -> LightDef {
+        LightDef { is_named_light, ..LightDef::test_point(name, Vec3::ZERO) }
+    }
+
+    fn cluster(name: &str, surface_id: u64, lights: Vec<LightDef>, x: f32) -> LightCluster {
+        let mut bound = AABB::new();
+        bound.extend(Vec3::new(x, 0.0, 0.0));
+        bound.extend(Vec3::new(x + 64.0, 64.0, 0.0));
+
+        LightCluster {
+            solids: Vec::new(),
+            ggx_surface_name: format!("surface_{}", surface_id),
+            ggx_surface_id: surface_id,
+            ggx_surface_origin: Vec3::ZERO,
+            name: name.to_string(),
+            bound,
+            lights: lights.into_iter().map(|l| (l, 1.0)).collect(),
+            initial_c4: [1.0; 4],
+            pbr_material: "pbr/floor".to_string(),
+            surface_material: name.to_string(),
+            surface_material_path: Default::default(),
+            min_cluster_score: 0.1,
+            rejected_lights: Vec::new(),
+            ambient_sh: [Vec3::ZERO; 4],
+            explanations: Vec::new(),
+            pcc_volume: None,
+            cubemap_name: None,
+            pcc_blend: None,
+            blend_cubemap_name: None,
+        }
+    }
+
+    #[test]
+    fn test_hidden_surfaces_are_not_baked() {
+        let mut vmf = VmfFile::default();
+        vmf.visgroups.groups = vec![VisGroup { name: "Night".to_string(), id: 1, ..Default::default() }];
+        let mut hidden = Entity::new("func_ggx_surface", 2);
+        hidden.set("visgroupid".to_string(), "1".to_string());
+        vmf.entities.0 = vec![Entity::new("func_ggx_surface", 1), hidden, Entity::new("light", 3)];
+
+        let filter = VisgroupFilter::new(Vec::new(), vec!["night".to_string()]);
+        let visible = visgroups::visible_copy(&vmf, &filter);
+        let (surfaces, rest) = GgxSurfaceEnt::partition_visible(vmf.entities.0, &visible);
+        assert_eq!(surfaces.iter().map(Entity::id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(rest.iter().map(Entity::id).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_unnamed_surfaces_get_free_names() {
+        let mut empty = Entity::new("func_ggx_surface", 100);
+        empty.set("template_material".to_string(), "pbr/floor".to_string());
+        empty.set("targetname".to_string(), String::new());
+        empty.solids = Some(Vec::new());
+        let mut taken = Entity::new("logic_relay", 101);
+        taken.set("targetname".to_string(), "Surface_0".to_string());
+
+        let surfaces = GgxSurfaceEnt::from_entities(vec![empty], &[taken]);
+        assert_eq!(surfaces[0].name, "surface_1");
+    }
+
+    #[test]
+    fn test_split_solids_get_fresh_ids() {
+        let mut aabb = AABB::new();
+        aabb.extend(Vec3::new(0.0, 0.0, -16.0));
+        aabb.extend(Vec3::new(512.0, 128.0, 0.0));
+        let solid = geometry::box_solid(&OrientedBox::from_aabb(&aabb), TARGET_MATERIAL, &mut 1);
+
+        let mut ent = Entity::new("func_ggx_surface", 100);
+        ent.set("template_material".to_string(), "pbr/floor".to_string());
+        ent.solids = Some(vec![solid.clone()]);
+        let mut surface = GgxSurfaceEnt::from_entities(vec![ent], &[]).remove(0);
+
+        let (front, back) = geometry::split_solid(&solid, aabb.center, Vec3::new(1.0, 0.0, 0.0), SPLIT_CAP_MATERIAL).unwrap();
+        surface.ggx_solids = vec![Arc::new(RwLock::new(GgxSolid::new(front))), Arc::new(RwLock::new(GgxSolid::new(back)))];
+        surface.renumber_split_solids(&mut 1000);
+
+        let solids: Vec<Solid> = surface.ggx_solids.iter().map(|s| s.read().unwrap().solid.clone()).collect();
+        assert_eq!(solids[0].id, 1);
+        assert!(solids[1].id >= 1000);
+        let side_ids: Vec<u32> = solids.iter().flat_map(|s| s.sides.iter().map(|side| side.id)).collect();
+        let unique: HashSet<u32> = side_ids.iter().copied().collect();
+        assert_eq!(unique.len(), side_ids.len());
+        assert!(!unique.contains(&0));
+    }
+
+    #[test]
+    fn test_merge_identical_clusters() {
+        let (a, b, named) = (light("a", false), light("b", false), light("lamp", true));
+        let mut shaded = light("b", false);
+        shaded.blockers = vec![BlockerDef {
+            id: 9,
+            width: 16.0,
+            height: 16.0,
+            depth: 16.0,
+            pos: Vec3::ZERO,
+            axes: OrientedBox::WORLD_AXES,
+            kind: BlockerType::Opaque,
+            tint: Vec3::ONE,
+        }];
+        let clusters = vec![
+            cluster("s0", 1, vec![a.clone(), b.clone()], 0.0),
+            cluster("s1", 2, vec![b, a.clone()], 128.0),
+            cluster("s2", 2, vec![a.clone()], 256.0),
+            // Named lights only merge inside the same surface
+            cluster("s3", 3, vec![named.clone()], 0.0),
+            cluster("s4", 4, vec![named.clone()], 0.0),
+            cluster("s5", 4, vec![named], 64.0),
+            // Same lights, but another blocker is in the way
+            cluster("s6", 5, vec![a, shaded], 512.0),
+        ];
+
+        let merged = LightCluster::merge_identical(clusters);
+        let names: Vec<&str> = merged.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["s0", "s2", "s3", "s4", "s6"]);
+        assert_eq!(merged[0].bound.max[0], 192.0);
+        assert_eq!(merged[3].bound.max[0], 128.0);
+
+        // Ambient of merged clusters is taken at the new center
+        let mut clusters = vec![cluster("s0", 1, Vec::new(), 0.0), cluster("s1", 1, Vec::new(), 512.0)];
+        for c in clusters.iter_mut() {
+            c.rejected_lights = vec![(LightDef { pos: Vec3::new(288.0, 32.0, 128.0), ..light("dim", false) }, 0.01)];
+            c.ambient_sh = ambient_residual(&c.rejected_lights, c.bound.center);
+        }
+        let merged = LightCluster::merge_identical(clusters);
+        assert_eq!(merged[0].ambient_sh, ambient_residual(&merged[0].rejected_lights, merged[0].bound.center));
+        assert!(merged[0].ambient_sh[3][0].abs() < 1e-4); // The light is right above the new center
+
+        // Each cluster has its own dim light left over, the merged ambient has both
+        let mut clusters = vec![cluster("s0", 1, Vec::new(), 0.0), cluster("s1", 1, Vec::new(), 512.0)];
+        clusters[0].rejected_lights = vec![(LightDef { pos: Vec3::new(32.0, 32.0, 64.0), ..light("dim_0", false) }, 0.01)];
+        clusters[1].rejected_lights = vec![(LightDef { pos: Vec3::new(544.0, 32.0, 64.0), ..light("dim_1", false) }, 0.01)];
+        let merged = LightCluster::merge_identical(clusters);
+        let rejected: Vec<&str> = merged[0].rejected_lights.iter().map(|(l, _)| l.pbr_name.as_str()).collect();
+        assert_eq!(rejected, vec!["dim_0", "dim_1"]);
+        assert!(merged[0].ambient_sh[3][0].abs() < 1e-4); // Symmetric around the center, no X gradient
+        assert!(merged[0].ambient_sh[0][0] > 0.0);
+    }
 }
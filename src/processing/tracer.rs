@@ -1,8 +1,13 @@
 use crate::math::{Vec3, AABB};
-use crate::processing::geometry::ConvexBrush;
+use crate::processing::displacement::{self, DispMesh};
+use crate::processing::geometry::{CollisionWorld, ConvexBrush};
 use log::debug;
 
 const EPSILON: f32 = 0.001;
+// Displacements have no thickness, so ignore hits right at the ray ends (e.g. samples lying on the terrain)
+const DISP_EPSILON: f32 = 0.01;
+// How far to look for the sky. Bigger than the diagonal of the largest Source map
+pub const SKY_TRACE_DIST: f32 = 65536.0;
 
 pub struct RayHit<'a> {
     pub id: u64,
@@ -11,9 +16,9 @@ pub struct RayHit<'a> {
     pub v_axis: &'a str,
 }
 
-/// Checks whether the path from `start` to `end` is blocked by the `brushes` geometry.
+/// Checks whether the path from `start` to `end` is blocked by the `world` geometry.
 /// Returns true if the path is blocked (i.e., there is a shadow)
-pub fn is_occluded(start: Vec3, end: Vec3, brushes: &[ConvexBrush]) -> bool {
+pub fn is_occluded(start: Vec3, end: Vec3, world: &CollisionWorld) -> bool {
     let diff = end - start;
     let dist_sq = diff.dot(diff);
     let dist = dist_sq.sqrt();
@@ -25,35 +30,125 @@ pub fn is_occluded(start: Vec3, end: Vec3, brushes: &[ConvexBrush]) -> bool {
 
     let dir = Vec3::new(diff[0] / dist, diff[1] / dist, diff[2] / dist);
 
-    for brush in brushes.iter() {
-        // Broad Phase AABB Check
-        if !ray_aabb_intersect(start, dir, dist, &brush._bounds) {
+    let mut occluded = false;
+    world.bvh.traverse_ray(start, dir, dist, |idx| {
+        occluded = brush_occludes(start, end, dir, dist, &world.brushes[idx]);
+        !occluded
+    });
+    if occluded {
+        return true;
+    }
+
+    world.disp_bvh.traverse_ray(start, dir, dist, |idx| {
+        let disp = &world.displacements[idx];
+        occluded = closest_disp_hit(start, dir, dist, disp).is_some_and(|t| t < dist - DISP_EPSILON);
+        if occluded {
+            debug!("      - Ray from {:?} to {:?} is occluded by displacement #{} ({})", start, end, disp.id, disp.material);
+        }
+        !occluded
+    });
+    occluded
+}
+
+pub fn trace_ray_closest<'a>(start: Vec3, dir: Vec3, max_dist: f32, world: &'a CollisionWorld) -> Option<RayHit<'a>> {
+    // Candidates are visited in brush order, so ties resolve exactly like a linear scan
+    let candidates = world.bvh.query_ray(start, dir, max_dist);
+    let mut hit_data = closest_hit(start, dir, max_dist, candidates.into_iter().map(|idx| &world.brushes[idx]));
+
+    let mut closest_t = hit_data.as_ref().map(|hit| hit.t).unwrap_or(max_dist);
+    for idx in world.disp_bvh.query_ray(start, dir, max_dist) {
+        let disp = &world.displacements[idx];
+        if let Some(t) = closest_disp_hit(start, dir, closest_t, disp)
+            && t < closest_t {
+                closest_t = t;
+                hit_data = Some(RayHit {
+                    t,
+                    id: disp.id,
+                    u_axis: &disp.u_axis,
+                    v_axis: &disp.v_axis,
+                });
+            }
+    }
+
+    hit_data
+}
+
+/// Checks whether a ray from `start` along `dir` reaches a `tools/toolsskybox` face without being occluded.
+/// Used for directional (sun) lights
+pub fn is_sky_visible(start: Vec3, dir: Vec3, world: &CollisionWorld) -> bool {
+    let mut sky_t: Option<f32> = None;
+
+    for idx in world.bvh.query_ray(start, dir, SKY_TRACE_DIST) {
+        let brush = &world.brushes[idx];
+        if !brush.planes.iter().any(|p| is_sky_material(&p.material)) {
             continue;
         }
-        if let Some((_, plane_idx)) = intersect_brush(start, dir, dist, brush) {
-            let plane = &brush.planes[plane_idx];
-            let mat_lower = plane.material.to_lowercase();
-            if mat_lower.contains("tools") && !mat_lower.contains("nodraw") && !mat_lower.contains("pbr_block") {
-                continue; // what the tool skybox bruh fuck u
+
+        // Sky brushes are made of tools textures, so all planes take part here
+        if let Some((t_near, plane_idx)) = intersect_brush_planes(start, dir, SKY_TRACE_DIST, brush, true)
+            && t_near >= 0.0
+            && is_sky_material(&brush.planes[plane_idx].material)
+            && sky_t.is_none_or(|t| t_near < t) {
+                sky_t = Some(t_near);
             }
+    }
+
+    match sky_t {
+        Some(t) => !is_occluded(start, start + dir * t, world),
+        None => false,
+    }
+}
 
-            if mat_lower.contains("glass") {
-                debug!("    -> Ignored: Glass texture '{}'", plane.material);
-                continue;
+fn is_sky_material(material: &str) -> bool {
+    material.to_lowercase().contains("toolsskybox")
+}
+
+/// Closest triangle hit of a displacement within `(DISP_EPSILON, max_dist]`
+fn closest_disp_hit(start: Vec3, dir: Vec3, max_dist: f32, disp: &DispMesh) -> Option<f32> {
+    let mat_lower = disp.material.to_lowercase();
+    if (mat_lower.contains("tools") && !mat_lower.contains("nodraw") && !mat_lower.contains("pbr_block")) || mat_lower.contains("glass") {
+        return None;
+    }
+
+    let mut closest = None;
+    disp.bvh.traverse_ray(start, dir, max_dist, |tri_idx| {
+        if let Some(t) = displacement::intersect_triangle(start, dir, &disp.triangles[tri_idx])
+            && t > DISP_EPSILON && t <= max_dist && closest.is_none_or(|c| t < c) {
+                closest = Some(t);
             }
+        true
+    });
+    closest
+}
 
-            debug!("      - Ray from {:?} to {:?} is occluded by brush #{} ({})", start, end, brush.id, plane.material);
-            return true; // Shadow found
-         }
+fn brush_occludes(start: Vec3, end: Vec3, dir: Vec3, dist: f32, brush: &ConvexBrush) -> bool {
+    // Broad Phase AABB Check
+    if !ray_aabb_intersect(start, dir, dist, &brush._bounds) {
+        return false;
+    }
+    if let Some((_, plane_idx)) = intersect_brush(start, dir, dist, brush) {
+        let plane = &brush.planes[plane_idx];
+        let mat_lower = plane.material.to_lowercase();
+        if mat_lower.contains("tools") && !mat_lower.contains("nodraw") && !mat_lower.contains("pbr_block") {
+            return false; // what the tool skybox bruh fuck u
+        }
+
+        if mat_lower.contains("glass") {
+            debug!("    -> Ignored: Glass texture '{}'", plane.material);
+            return false;
+        }
+
+        debug!("      - Ray from {:?} to {:?} is occluded by brush #{} ({})", start, end, brush.id, plane.material);
+        return true; // Shadow found
     }
     false
 }
 
-pub fn trace_ray_closest<'a>(start: Vec3, dir: Vec3, max_dist: f32, brushes: &'a [ConvexBrush]) -> Option<RayHit<'a>> {
+fn closest_hit<'a>(start: Vec3, dir: Vec3, max_dist: f32, brushes: impl Iterator<Item = &'a ConvexBrush>) -> Option<RayHit<'a>> {
     let mut closest_t = max_dist;
     let mut hit_data = None;
 
-    for brush in brushes.iter() {
+    for brush in brushes {
         if !ray_aabb_intersect(start, dir, max_dist, &brush._bounds) {
             continue;
         }
@@ -83,7 +178,7 @@ pub fn trace_ray_closest<'a>(start: Vec3, dir: Vec3, max_dist: f32, brushes: &'a
     hit_data
 }
 
-fn ray_aabb_intersect(origin: Vec3, dir: Vec3, max_dist: f32, aabb: &AABB) -> bool {
+pub(crate) fn ray_aabb_intersect(origin: Vec3, dir: Vec3, max_dist: f32, aabb: &AABB) -> bool {
     let mut tmin = 0.0_f32;
     let mut tmax = max_dist;
     for i in 0..3 {
@@ -104,6 +199,10 @@ fn ray_aabb_intersect(origin: Vec3, dir: Vec3, max_dist: f32, aabb: &AABB) -> bo
 
 
 fn intersect_brush(origin: Vec3, dir: Vec3, max_dist: f32, brush: &ConvexBrush) -> Option<(f32, usize)> {
+    intersect_brush_planes(origin, dir, max_dist, brush, false)
+}
+
+fn intersect_brush_planes(origin: Vec3, dir: Vec3, max_dist: f32, brush: &ConvexBrush, include_tools: bool) -> Option<(f32, usize)> {
     let mut t_near = -std::f32::MAX;
     let mut t_far = max_dist;
     let mut enter_plane_idx = None;
@@ -111,7 +210,7 @@ fn intersect_brush(origin: Vec3, dir: Vec3, max_dist: f32, brush: &ConvexBrush)
     for (i, plane) in brush.planes.iter().enumerate() {
         let mat_lower = plane.material.to_lowercase();
         // Filter out tools textures - they cannot be hit
-        if mat_lower.contains("tools") && !mat_lower.contains("nodraw") && !mat_lower.contains("pbr_block") {
+        if !include_tools && mat_lower.contains("tools") && !mat_lower.contains("nodraw") && !mat_lower.contains("pbr_block") {
             continue;
         }
 
@@ -147,7 +246,7 @@ fn intersect_brush(origin: Vec3, dir: Vec3, max_dist: f32, brush: &ConvexBrush)
 mod tests {
     use super::*;
     use crate::math::AABB;
-    use crate::processing::geometry::{ConvexBrush, Plane};
+    use crate::processing::geometry::{CollisionWorld, ConvexBrush, Plane};
 
     // Helper to create a cube sized from -size to +size on all axes
     fn create_test_cube(size: f32) -> ConvexBrush {
@@ -180,7 +279,7 @@ mod tests {
     fn test_direct_hit() {
         // 10x10x10 cube at the center (from -10 to 10)
         let cube = create_test_cube(10.0);
-        let world = vec![cube];
+        let world = CollisionWorld::new(vec![cube], Vec::new());
 
         // Ray through the cube: from -20 to +20 along X
         let start = Vec3::new(-20.0, 0.0, 0.0);
@@ -193,7 +292,7 @@ mod tests {
     #[test]
     fn test_miss_side() {
         let cube = create_test_cube(10.0);
-        let world = vec![cube];
+        let world = CollisionWorld::new(vec![cube], Vec::new());
 
         // Ray from the side: from -20 to +20, but Y=15 (misses the cube)
         let start = Vec3::new(-20.0, 15.0, 0.0);
@@ -205,7 +304,7 @@ mod tests {
     #[test]
     fn test_short_ray_before() {
         let cube = create_test_cube(10.0);
-        let world = vec![cube];
+        let world = CollisionWorld::new(vec![cube], Vec::new());
 
         // Ray directed at the wall but does not reach it
         // Wall starts at X=-10. Ray from -30 to -15.
@@ -218,7 +317,7 @@ mod tests {
     #[test]
     fn test_inside_out() {
         let cube = create_test_cube(10.0);
-        let world = vec![cube];
+        let world = CollisionWorld::new(vec![cube], Vec::new());
 
         // Ray starts INSIDE the cube and goes out.
         // This is a debatable case (light inside a wall?), but technically it crosses a boundary.
@@ -245,7 +344,7 @@ mod tests {
     #[test]
     fn test_grazing_miss() {
         let cube = create_test_cube(10.0);
-        let world = vec![cube];
+        let world = CollisionWorld::new(vec![cube], Vec::new());
 
         // Ray runs parallel to the face, but slightly above (Y=10.001)
         let start = Vec3::new(-20.0, 10.1, 0.0);
@@ -257,7 +356,7 @@ mod tests {
     #[test]
     fn test_ray_starts_on_surface_and_goes_away() {
         let cube = create_test_cube(10.0);
-        let world = vec![cube];
+        let world = CollisionWorld::new(vec![cube], Vec::new());
 
         // Ray starts on the surface (X=-10) and goes outward (towards -X)
         let start = Vec3::new(-10.0, 0.0, 0.0);
@@ -266,4 +365,119 @@ mod tests {
         // Such a ray should NOT be considered occluded
         assert!(!is_occluded(start, end, &world), "Ray starting on surface and moving away should NOT be occluded");
     }
+
+    // Same cube as `create_test_cube`, but moved to `center`
+    fn create_offset_cube(id: u64, center: Vec3, size: f32) -> ConvexBrush {
+        let mut cube = create_test_cube(size);
+        cube.id = id;
+        for plane in cube.planes.iter_mut() {
+            plane.dist -= plane.normal.dot(center);
+        }
+        cube._bounds = AABB::new();
+        cube._bounds.extend(center - Vec3::new(size, size, size));
+        cube._bounds.extend(center + Vec3::new(size, size, size));
+        cube
+    }
+
+    // Grid of small cubes, roughly what a big map with lots of detail looks like
+    fn create_synthetic_world(per_axis: usize) -> CollisionWorld {
+        let mut brushes = Vec::new();
+        for x in 0..per_axis {
+            for y in 0..per_axis {
+                for z in 0..per_axis {
+                    let center = Vec3::new(x as f32 * 96.0, y as f32 * 96.0, z as f32 * 96.0);
+                    brushes.push(create_offset_cube(brushes.len() as u64, center, 16.0 + (x + y + z) as f32 % 3.0 * 8.0));
+                }
+            }
+        }
+        CollisionWorld::new(brushes, Vec::new())
+    }
+
+    // Deterministic pseudo-random rays across the synthetic world
+    fn create_rays(count: usize, extent: f32) -> Vec<(Vec3, Vec3)> {
+        let mut seed = 0x2545F491_u32;
+        let mut rand = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            (seed as f32 / u32::MAX as f32) * extent
+        };
+        (0..count).map(|_| (Vec3::new(rand(), rand(), rand()), Vec3::new(rand(), rand(), rand()))).collect()
+    }
+
+    fn linear_is_occluded(start: Vec3, end: Vec3, world: &CollisionWorld) -> bool {
+        let diff = end - start;
+        let dist = diff.length();
+        if dist < EPSILON {
+            return false;
+        }
+        let dir = diff / dist;
+        world.brushes.iter().any(|brush| brush_occludes(start, end, dir, dist, brush))
+    }
+
+    #[test]
+    fn test_bvh_matches_linear_scan() {
+        let world = create_synthetic_world(8);
+        for (start, end) in create_rays(2000, 8.0 * 96.0) {
+            assert_eq!(
+                is_occluded(start, end, &world),
+                linear_is_occluded(start, end, &world),
+                "is_occluded mismatch for ray {} -> {}", start, end
+            );
+
+            let dir = (end - start).normalize();
+            let max_dist = start.distance(end);
+            let bvh_hit = trace_ray_closest(start, dir, max_dist, &world).map(|h| (h.id, h.t));
+            let linear_hit = closest_hit(start, dir, max_dist, world.brushes.iter()).map(|h| (h.id, h.t));
+            assert_eq!(bvh_hit, linear_hit, "trace_ray_closest mismatch for ray {} -> {}", start, end);
+        }
+    }
+
+    #[test]
+    fn test_sky_visibility() {
+        // Sky brush high above, a wall cube between the sky and one of the points
+        let mut sky = create_offset_cube(1, Vec3::new(0.0, 0.0, 1000.0), 500.0);
+        for plane in sky.planes.iter_mut() {
+            plane.material = "TOOLS/TOOLSSKYBOX".to_string();
+        }
+        let wall = create_offset_cube(2, Vec3::new(200.0, 0.0, 200.0), 32.0);
+        let world = CollisionWorld::new(vec![sky, wall], Vec::new());
+        let up = Vec3::new(0.0, 0.0, 1.0);
+
+        assert!(is_sky_visible(Vec3::ZERO, up, &world), "Open point should see the sky");
+        assert!(!is_sky_visible(Vec3::new(200.0, 0.0, 0.0), up, &world), "Point under the wall should not see the sky");
+        assert!(!is_sky_visible(Vec3::ZERO, up * -1.0, &world), "No sky below");
+    }
+
+    #[test]
+    fn test_empty_world() {
+        let world = CollisionWorld::new(Vec::new(), Vec::new());
+        assert!(!is_occluded(Vec3::ZERO, Vec3::new(100.0, 0.0, 0.0), &world));
+        assert!(trace_ray_closest(Vec3::ZERO, Vec3::new(1.0, 0.0, 0.0), 100.0, &world).is_none());
+    }
+
+    // Run with: cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_bvh_vs_linear() {
+        use std::time::Instant;
+
+        let world = create_synthetic_world(16); // 4096 brushes
+        let rays = create_rays(20_000, 16.0 * 96.0);
+
+        let timer = Instant::now();
+        let linear_count = rays.iter().filter(|(s, e)| linear_is_occluded(*s, *e, &world)).count();
+        let linear_time = timer.elapsed();
+
+        let timer = Instant::now();
+        let bvh_count = rays.iter().filter(|(s, e)| is_occluded(*s, *e, &world)).count();
+        let bvh_time = timer.elapsed();
+
+        println!("{} brushes, {} rays ({} occluded)", world.brushes.len(), rays.len(), bvh_count);
+        println!("  linear: {:?}", linear_time);
+        println!("  bvh:    {:?} (x{:.1} faster)", bvh_time, linear_time.as_secs_f64() / bvh_time.as_secs_f64());
+
+        assert_eq!(linear_count, bvh_count);
+        assert!(bvh_time < linear_time);
+    }
 }
@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+
+use log::{debug, info, warn};
+use vmf_forge::prelude::*;
+
+use crate::text::wildcard_match;
+
+/// Visgroups forced on or off from the command line, matched by name (wildcards allowed)
+#[derive(Debug, Clone, Default)]
+pub struct VisgroupFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl VisgroupFilter {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        Self { include, exclude }
+    }
+}
+
+// Resolved visgroup IDs of one VMF file
+struct VisgroupRules {
+    include: HashSet<i32>,
+    exclude: HashSet<i32>,
+}
+
+impl VisgroupRules {
+    fn new(filter: &VisgroupFilter, visgroups: &VisGroups, warn_unmatched: bool) -> Self {
+        let resolve = |patterns: &[String]| -> HashSet<i32> {
+            let mut ids = HashSet::new();
+            for pattern in patterns {
+                let found = collect_matching(&visgroups.groups, pattern, false, &mut ids);
+                if !found && warn_unmatched {
+                    warn!("Visgroup '{}' not found in the VMF.", pattern);
+                }
+            }
+            ids
+        };
+
+        Self { include: resolve(&filter.include), exclude: resolve(&filter.exclude) }
+    }
+
+    /// Excluded groups win over included ones, included ones win over the editor state
+    fn is_visible(&self, visgroups: &[i32], shown: bool) -> bool {
+        if visgroups.iter().any(|id| self.exclude.contains(id)) {
+            return false;
+        }
+        if visgroups.iter().any(|id| self.include.contains(id)) {
+            return true;
+        }
+        shown
+    }
+
+    fn is_forced(&self, visgroups: &[i32]) -> bool {
+        visgroups.iter().any(|id| self.include.contains(id))
+    }
+
+    /// Visibility set by the filter, if any. Exclusion wins, like in `is_visible`
+    fn forced_state(&self, visgroups: &[i32]) -> Option<bool> {
+        if visgroups.iter().any(|id| self.exclude.contains(id)) {
+            Some(false)
+        } else {
+            self.is_forced(visgroups).then_some(true)
+        }
+    }
+}
+
+// Collects IDs of matching visgroups and all of their children. Returns true if anything matched
+fn collect_matching(groups: &[VisGroup], pattern: &str, parent_matched: bool, ids: &mut HashSet<i32>) -> bool {
+    let mut found = false;
+    for group in groups {
+        let matched = parent_matched || wildcard_match(pattern, &group.name);
+        if matched {
+            ids.insert(group.id);
+            found = true;
+        }
+        if let Some(children) = &group.children {
+            found |= collect_matching(children, pattern, matched, ids);
+        }
+    }
+    found
+}
+
+/// Removes everything the mapper hid in Hammer (hidden visgroups and "Hide objects"), like VBSP does.
+/// Objects of included visgroups are marked visible instead, so VBSP compiles them too.
+/// Returns the number of removed solids and entities
+pub fn strip_hidden(vmf: &mut VmfFile, filter: &VisgroupFilter) -> usize {
+    strip_hidden_impl(vmf, filter, true)
+}
+
+/// Applies `--include-visgroup` / `--exclude-visgroup` to the VMF itself, without removing anything:
+/// objects of excluded visgroups get hidden, objects of included ones shown.
+/// The saved map then compiles the same variant that was baked
+pub fn apply_overrides(vmf: &mut VmfFile, filter: &VisgroupFilter) {
+    let rules = VisgroupRules::new(filter, &vmf.visgroups, false);
+    if rules.include.is_empty() && rules.exclude.is_empty() {
+        return;
+    }
+
+    let override_solid = |solid: &mut Solid| {
+        let visgroups: Vec<i32> = solid.editor.visgroup_id.into_iter().collect();
+        if let Some(shown) = rules.forced_state(&visgroups) {
+            solid.editor.visgroup_shown = shown;
+            solid.editor.visgroup_auto_shown = true;
+        }
+    };
+
+    vmf.world.solids.iter_mut().for_each(override_solid);
+    for ent in vmf.entities.iter_mut() {
+        let (visgroups, _) = entity_visgroups(ent);
+        if let Some(shown) = rules.forced_state(&visgroups) {
+            ent.set("visgroupshown".to_string(), if shown { "1" } else { "0" }.to_string());
+            ent.set("visgroupautoshown".to_string(), "1".to_string());
+        }
+        ent.solids.iter_mut().flatten().for_each(override_solid);
+    }
+}
+
+/// Copy of the VMF without the hidden objects. Lights and the collision world are built from it,
+/// while the VMF that gets saved keeps everything
+pub fn visible_copy(vmf: &VmfFile, filter: &VisgroupFilter) -> VmfFile {
+    let mut visible = vmf.clone();
+    strip_hidden(&mut visible, filter);
+    visible
+}
+
+/// Same as `strip_hidden`, but quiet about filter names missing in this file (instances, submaps)
+pub(crate) fn strip_hidden_nested(vmf: &mut VmfFile, filter: &VisgroupFilter) -> usize {
+    strip_hidden_impl(vmf, filter, false)
+}
+
+fn strip_hidden_impl(vmf: &mut VmfFile, filter: &VisgroupFilter, verbose: bool) -> usize {
+    let rules = VisgroupRules::new(filter, &vmf.visgroups, verbose);
+    let mut removed = 0;
+
+    removed += retain_visible_solids(&mut vmf.world.solids, &rules);
+
+    vmf.entities.retain_mut(|ent| {
+        if ent.hidden {
+            removed += 1;
+            return false;
+        }
+
+        let (visgroups, shown) = entity_visgroups(ent);
+        if !rules.is_visible(&visgroups, shown) {
+            debug!("Skipping hidden entity {} ({:?})", ent.id(), ent.classname());
+            removed += 1;
+            return false;
+        }
+        if rules.is_forced(&visgroups) {
+            ent.set("visgroupshown".to_string(), "1".to_string());
+        }
+
+        // Brush entities lose hidden brushes, and go away completely if nothing is left
+        if let Some(solids) = ent.solids.as_mut()
+            && !solids.is_empty()
+        {
+            removed += retain_visible_solids(solids, &rules);
+            if solids.is_empty() {
+                debug!("Skipping entity {} ({:?}): all brushes are hidden", ent.id(), ent.classname());
+                removed += 1;
+                return false;
+            }
+        }
+        true
+    });
+
+    if removed > 0 && verbose {
+        info!("Skipped {} hidden solids and entities.", removed);
+    }
+    removed
+}
+
+fn retain_visible_solids(solids: &mut Vec<Solid>, rules: &VisgroupRules) -> usize {
+    let before = solids.len();
+    solids.retain_mut(|solid| {
+        let editor = &mut solid.editor;
+        let visgroups: Vec<i32> = editor.visgroup_id.into_iter().collect();
+        let shown = editor.visgroup_shown && editor.visgroup_auto_shown;
+
+        if !rules.is_visible(&visgroups, shown) {
+            return false;
+        }
+        if rules.is_forced(&visgroups) {
+            editor.visgroup_shown = true;
+            editor.visgroup_auto_shown = true;
+        }
+        true
+    });
+    before - solids.len()
+}
+
+// Entity editor data is stored next to the regular keyvalues
+pub(crate) fn entity_visgroups(ent: &Entity) -> (Vec<i32>, bool) {
+    let visgroups = ent.get("visgroupid")
+        .map(|s| s.split_whitespace().filter_map(|v| v.parse().ok()).collect())
+        .unwrap_or_default();
+    let shown = ent.get("visgroupshown").map(|v| v.trim() != "0").unwrap_or(true)
+        && ent.get("visgroupautoshown").map(|v| v.trim() != "0").unwrap_or(true);
+
+    (visgroups, shown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_in(id: u64, visgroup: Option<i32>, shown: bool) -> Solid {
+        Solid {
+            id,
+            editor: Editor { visgroup_id: visgroup, visgroup_shown: shown, visgroup_auto_shown: true, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    fn test_vmf() -> VmfFile {
+        let mut vmf = VmfFile::default();
+        vmf.visgroups.groups = vec![
+            VisGroup { name: "Night Lights".to_string(), id: 1, ..Default::default() },
+            VisGroup {
+                name: "Props".to_string(),
+                id: 2,
+                children: Some(vec![VisGroup { name: "Crates".to_string(), id: 3, ..Default::default() }]),
+                ..Default::default()
+            },
+        ];
+        vmf.world.solids = vec![
+            solid_in(10, None, true),
+            solid_in(11, Some(1), false),
+            solid_in(12, Some(3), true),
+        ];
+
+        let mut lamp = Entity::new("light", 20);
+        lamp.set("visgroupid".to_string(), "1".to_string());
+        lamp.set("visgroupshown".to_string(), "0".to_string());
+        let mut hidden = Entity::new("light", 21);
+        hidden.hidden = true;
+        vmf.entities.0 = vec![lamp, hidden, Entity::new("light", 22)];
+        vmf
+    }
+
+    #[test]
+    fn test_hidden_objects_are_skipped() {
+        let mut vmf = test_vmf();
+        assert_eq!(strip_hidden(&mut vmf, &VisgroupFilter::default()), 3);
+        assert_eq!(vmf.world.solids.iter().map(|s| s.id).collect::<Vec<_>>(), vec![10, 12]);
+        assert_eq!(vmf.entities.iter().map(Entity::id).collect::<Vec<_>>(), vec![22]);
+    }
+
+    #[test]
+    fn test_include_and_exclude_visgroups() {
+        let mut vmf = test_vmf();
+        let filter = VisgroupFilter::new(vec!["night*".to_string()], vec!["props".to_string()]);
+        strip_hidden(&mut vmf, &filter);
+
+        // Hidden night lights are forced on, crates go away together with their parent group
+        assert_eq!(vmf.world.solids.iter().map(|s| s.id).collect::<Vec<_>>(), vec![10, 11]);
+        assert!(vmf.world.solids[1].editor.visgroup_shown);
+        assert_eq!(vmf.entities.iter().map(Entity::id).collect::<Vec<_>>(), vec![20, 22]);
+    }
+
+    #[test]
+    fn test_overrides_are_saved() {
+        let mut vmf = test_vmf();
+        let filter = VisgroupFilter::new(vec!["night*".to_string()], vec!["props".to_string()]);
+        apply_overrides(&mut vmf, &filter);
+
+        // Nothing is removed, the overrides only flip the editor state
+        assert_eq!(vmf.world.solids.len(), 3);
+        assert!(vmf.world.solids[1].editor.visgroup_shown);
+        assert!(!vmf.world.solids[2].editor.visgroup_shown);
+        assert_eq!(vmf.entities[0].get("visgroupshown").unwrap(), "1");
+
+        // Stripping the saved VMF gives the baked variant
+        strip_hidden(&mut vmf, &VisgroupFilter::default());
+        assert_eq!(vmf.world.solids.iter().map(|s| s.id).collect::<Vec<_>>(), vec![10, 11]);
+        assert_eq!(vmf.entities.iter().map(Entity::id).collect::<Vec<_>>(), vec![20, 22]);
+    }
+
+    #[test]
+    fn test_visible_copy_keeps_the_original() {
+        let vmf = test_vmf();
+        let visible = visible_copy(&vmf, &VisgroupFilter::default());
+        assert_eq!(visible.world.solids.len(), 2);
+        assert_eq!(vmf.world.solids.len(), 3);
+        assert_eq!(vmf.entities.len(), 3);
+    }
+}
@@ -1,17 +1,60 @@
 use std::{path::PathBuf, sync::{Arc, RwLock}};
-use crate::math::{AABB, Vec3};
+use crate::math::{AABB, OrientedBox, Vec3};
+use crate::processing::scoring::LightExplanation;
 use crate::processing::surface_wrappers::GgxSolid;
 
-const MAX_BLOCKERS: usize = 2;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockerType {
+    Opaque,
+    Fizzler,
+    TintedGlass,
+}
+
+impl BlockerType {
+    /// Value of the type flag in the LUT
+    pub fn flag(&self) -> f32 {
+        match self {
+            BlockerType::Opaque => 1.0,
+            BlockerType::Fizzler => 2.0,
+            BlockerType::TintedGlass => 3.0,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "1" | "opaque" => Some(BlockerType::Opaque),
+            "2" | "fizzler" => Some(BlockerType::Fizzler),
+            "3" | "glass" | "tinted_glass" => Some(BlockerType::TintedGlass),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct BlockerDef {
+    // Hammer id of the blocker entity
+    pub id: u64,
+    // Sizes along the box local X, Y, Z axes
     pub width: f32,
     pub height: f32,
     pub depth: f32,
     // Center position of the blocker in the world
-    pub pos: Option<Vec3>,
-    pub flag: u8,
+    pub pos: Vec3,
+    // World space directions of the box local axes
+    pub axes: [Vec3; 3],
+    pub kind: BlockerType,
+    // Light color multiplier, used by tinted glass
+    pub tint: Vec3,
+}
+
+impl BlockerDef {
+    pub fn oriented_box(&self) -> OrientedBox {
+        OrientedBox {
+            center: self.pos,
+            axes: self.axes,
+            half_extents: Vec3::new(self.width, self.height, self.depth) * 0.5,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -25,10 +68,30 @@ pub enum LightType {
     },
     Rect {
         direction: Vec3,
+        // Width axis of the rectangle. Height axis is `right x direction`
+        right: Vec3,
         width: f32,
         height: f32,
         bidirectional: bool,
     },
+    /// Sun light from `light_environment`. `direction` is where the light travels to
+    Directional {
+        direction: Vec3,
+    },
+    Sphere {
+        radius: f32,
+    },
+    /// Capsule along `direction`, centered at the light position
+    Tube {
+        direction: Vec3,
+        length: f32,
+        radius: f32,
+    },
+    /// One-sided disk facing `direction`
+    Disk {
+        direction: Vec3,
+        radius: f32,
+    },
 }
 
 impl LightType {
@@ -37,6 +100,10 @@ impl LightType {
             LightType::Point => "Point",
             LightType::Spot { .. } => "Spot",
             LightType::Rect { .. } => "Area",
+            LightType::Directional { .. } => "Directional",
+            LightType::Sphere { .. } => "Sphere",
+            LightType::Tube { .. } => "Tube",
+            LightType::Disk { .. } => "Disk",
         }
     }
 }
@@ -49,6 +116,9 @@ pub struct LightDef {
     pub target_name: String,
     pub pbr_name: String,
     pub is_named_light: bool,
+    // Entity classname ("texlight" for `.rad` textures) and `pbr_light_group` names, matched by the force/exclude rules
+    pub classname: String,
+    pub groups: Vec<String>,
 
     pub light_type: LightType,
     pub pos: Vec3,
@@ -57,10 +127,67 @@ pub struct LightDef {
     pub range: f32,
     pub attenuation_k: f32,
     pub fifty_percent_distance: Option<f32>,
-    pub blockers: [Option<BlockerDef>; MAX_BLOCKERS],
+    // All blockers matched for this light. Clustering trims them down to the most relevant ones
+    pub blockers: Vec<BlockerDef>,
 
     /// If true, the light is turned off at map start (spawnflags & 1)
     pub initially_dark: bool,
+    /// Lighting channels bitmask (`pbr_channels`). Only surfaces receiving one of them consider the light
+    pub channels: u32,
+}
+
+impl LightDef {
+    /// Representative points on the emitting shape, used for distance and visibility tests.
+    /// Lights without a real extent in scoring only return `pos`
+    pub fn emitter_points(&self) -> Vec<Vec3> {
+        match self.light_type {
+            LightType::Tube { direction, length, .. } => {
+                let half = direction.normalize() * (length * 0.5);
+                vec![self.pos, self.pos - half, self.pos + half, self.pos - half * 0.5, self.pos + half * 0.5]
+            }
+            LightType::Disk { direction, radius } => {
+                let normal = direction.normalize();
+                let up_base = if normal[2].abs() > 0.99 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
+                let right = normal.cross(up_base).normalize() * radius;
+                let up = right.cross(normal).normalize() * radius;
+                vec![self.pos, self.pos + right, self.pos - right, self.pos + up, self.pos - up]
+            }
+            _ => vec![self.pos],
+        }
+    }
+
+    /// How far the shape extends around each of its emitter points
+    pub fn emitter_radius(&self) -> f32 {
+        match self.light_type {
+            LightType::Sphere { radius } | LightType::Tube { radius, .. } => radius,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl LightDef {
+    /// Plain white point light for tests. Override the fields a test cares about
+    pub(crate) fn test_point(pbr_name: &str, pos: Vec3) -> Self {
+        Self {
+            id: 1,
+            target_name: String::new(),
+            pbr_name: pbr_name.to_string(),
+            is_named_light: false,
+            classname: "light".to_string(),
+            groups: Vec::new(),
+            light_type: LightType::Point,
+            pos,
+            color: Vec3::ONE,
+            intensity: 1.0,
+            range: 512.0,
+            attenuation_k: 0.0,
+            fifty_percent_distance: None,
+            blockers: Vec::new(),
+            initially_dark: false,
+            channels: crate::constants::DEFAULT_LIGHT_CHANNELS,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -68,6 +195,9 @@ pub struct ParallaxCubemap {
     pub cubemap_pos: Vec3, // World space position of the selected env_cubemap
     pub ws_min: Vec3,      // World space AABB Min of the volume
     pub ws_max: Vec3,      // World space AABB Max of the volume
+    pub volume: OrientedBox, // The volume itself, rotated volumes only fit loosely into ws_min / ws_max
+    pub fallback: bool,    // No volume touches the surface, this is just the nearest one
+    pub parallax: bool,    // False for a plain env_cubemap without a volume, no box projection
 }
 
 /// Represents a collection of lights assigned to a specific surface/material.
@@ -91,9 +221,16 @@ pub struct LightCluster {
 
     pub min_cluster_score: f32,
     pub rejected_lights: Vec<(LightDef, f32)>,
+    // L1 SH of the rejected lights at the cluster center (L0, L1y, L1z, L1x)
+    pub ambient_sh: [Vec3; 4],
+    // Per-light score breakdown, only for surfaces picked with `--explain`
+    pub explanations: Vec<LightExplanation>,
 
     pub pcc_volume: Option<ParallaxCubemap>,
     pub cubemap_name: Option<String>,
+    // Cubemap of a neighbour volume and its blend factor, for surfaces between two rooms
+    pub pcc_blend: Option<(ParallaxCubemap, f32)>,
+    pub blend_cubemap_name: Option<String>,
 }
 
 impl LightCluster {
@@ -105,6 +242,10 @@ impl LightCluster {
         println!("   GGX_SURFACE entity: {:?} (hammer id: {})", self.ggx_surface_name, self.ggx_surface_id);
         println!("   Min Score Threshold: {:.4}", self.min_cluster_score);
         println!("   Cubemap Name: {:?}", self.cubemap_name.as_deref().unwrap_or("None"));
+        if let (Some(name), Some((_, factor))) = (&self.blend_cubemap_name, &self.pcc_blend) {
+            println!("   Blend Cubemap: {:?} ({:.2})", name, factor);
+        }
+        println!("   Ambient Residual (L0): {}", self.ambient_sh[0]);
 
         println!("   [ACCEPTED LIGHTS] (Count: {})", self.lights.len());
         for (light, score) in &self.lights {
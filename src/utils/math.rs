@@ -212,3 +212,173 @@ pub fn sq_dist_point_aabb(point: Vec3, aabb: &AABB) -> f32 {
 
     sq_dist
 }
+
+/// Box with arbitrary rotation. `axes` are the world space directions of the box local X, Y, Z
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrientedBox {
+    pub center: Vec3,
+    pub axes: [Vec3; 3],
+    pub half_extents: Vec3,
+}
+
+impl OrientedBox {
+    pub const WORLD_AXES: [Vec3; 3] = [Vec3(1.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0), Vec3(0.0, 0.0, 1.0)];
+
+    pub fn from_aabb(aabb: &AABB) -> Self {
+        Self {
+            center: aabb.center,
+            axes: Self::WORLD_AXES,
+            half_extents: (aabb.max - aabb.min) * 0.5,
+        }
+    }
+
+    /// Fits a box with the given (orthonormal) axes around the points
+    pub fn fit(points: &[Vec3], axes: [Vec3; 3]) -> Self {
+        let mut local = AABB::new();
+        for &p in points {
+            local.extend(Vec3::new(p.dot(axes[0]), p.dot(axes[1]), p.dot(axes[2])));
+        }
+
+        let c = local.center;
+        Self {
+            center: axes[0] * c[0] + axes[1] * c[1] + axes[2] * c[2],
+            axes,
+            half_extents: (local.max - local.min) * 0.5,
+        }
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.half_extents * 2.0
+    }
+
+    pub fn volume(&self) -> f32 {
+        let s = self.size();
+        s[0] * s[1] * s[2]
+    }
+
+    /// Point in the box local space (relative to the center)
+    pub fn to_local(&self, p: Vec3) -> Vec3 {
+        let d = p - self.center;
+        Vec3::new(d.dot(self.axes[0]), d.dot(self.axes[1]), d.dot(self.axes[2]))
+    }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        let l = self.to_local(p);
+        (0..3).all(|i| l[i].abs() <= self.half_extents[i])
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        std::array::from_fn(|i| {
+            let sign = |bit: usize| if i & (1 << bit) != 0 { 1.0 } else { -1.0 };
+            self.center
+                + self.axes[0] * (self.half_extents[0] * sign(0))
+                + self.axes[1] * (self.half_extents[1] * sign(1))
+                + self.axes[2] * (self.half_extents[2] * sign(2))
+        })
+    }
+
+    /// World space AABB enclosing the box
+    pub fn bounds(&self) -> AABB {
+        let mut aabb = AABB::new();
+        for p in self.corners() {
+            aabb.extend(p);
+        }
+        aabb
+    }
+
+    /// Rows of the affine world -> box transform, scaled so the box becomes [-1, 1] on every axis
+    pub fn world_to_unit_box(&self) -> [[f32; 4]; 3] {
+        std::array::from_fn(|i| {
+            let axis = self.axes[i] / self.half_extents[i].max(1e-3);
+            [axis[0], axis[1], axis[2], -axis.dot(self.center)]
+        })
+    }
+
+    /// Slab test of the segment `a -> b` against the box
+    pub fn intersects_segment(&self, a: Vec3, b: Vec3) -> bool {
+        let origin = self.to_local(a);
+        let dir = self.to_local(b) - origin;
+        let mut t_min = 0.0_f32;
+        let mut t_max = 1.0_f32;
+
+        for i in 0..3 {
+            if dir[i].abs() < 1e-6 {
+                if origin[i].abs() > self.half_extents[i] { return false; }
+            } else {
+                let ood = 1.0 / dir[i];
+                let mut t1 = (-self.half_extents[i] - origin[i]) * ood;
+                let mut t2 = (self.half_extents[i] - origin[i]) * ood;
+                if t1 > t2 { std::mem::swap(&mut t1, &mut t2); }
+                t_min = t_min.max(t1);
+                t_max = t_max.min(t2);
+                if t_min > t_max { return false; }
+            }
+        }
+        true
+    }
+
+    /// Rotation from box local space to world space as a quaternion (x, y, z, w)
+    pub fn rotation_quat(&self) -> [f32; 4] {
+        quat_from_basis(self.axes)
+    }
+}
+
+/// Converts a rotation matrix (given by its columns) into a quaternion (x, y, z, w)
+pub fn quat_from_basis(axes: [Vec3; 3]) -> [f32; 4] {
+    let [x, y, z] = axes;
+    let (m00, m11, m22) = (x[0], y[1], z[2]);
+    let trace = m00 + m11 + m22;
+
+    let q = if trace > 0.0 {
+        let s = (trace + 1.0).sqrt() * 2.0;
+        [(y[2] - z[1]) / s, (z[0] - x[2]) / s, (x[1] - y[0]) / s, 0.25 * s]
+    } else if m00 > m11 && m00 > m22 {
+        let s = (1.0 + m00 - m11 - m22).sqrt() * 2.0;
+        [0.25 * s, (y[0] + x[1]) / s, (z[0] + x[2]) / s, (y[2] - z[1]) / s]
+    } else if m11 > m22 {
+        let s = (1.0 + m11 - m00 - m22).sqrt() * 2.0;
+        [(y[0] + x[1]) / s, 0.25 * s, (z[1] + y[2]) / s, (z[0] - x[2]) / s]
+    } else {
+        let s = (1.0 + m22 - m00 - m11).sqrt() * 2.0;
+        [(z[0] + x[2]) / s, (z[1] + y[2]) / s, 0.25 * s, (x[1] - y[0]) / s]
+    };
+
+    let len = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+    [q[0] / len, q[1] / len, q[2] / len, q[3] / len]
+}
+
+
+/// Source `AngleMatrix`: (pitch yaw roll) in degrees -> rotation columns (forward, left, up)
+pub fn angles_to_basis(angles: Vec3) -> [Vec3; 3] {
+    let (sp, cp) = angles[0].to_radians().sin_cos();
+    let (sy, cy) = angles[1].to_radians().sin_cos();
+    let (sr, cr) = angles[2].to_radians().sin_cos();
+
+    [
+        Vec3::new(cp * cy, cp * sy, -sp),
+        Vec3::new(sr * sp * cy - cr * sy, sr * sp * sy + cr * cy, sr * cp),
+        Vec3::new(cr * sp * cy + sr * sy, cr * sp * sy - sr * cy, cr * cp),
+    ]
+}
+
+/// Source `MatrixAngles`: inverse of `angles_to_basis`
+pub fn basis_to_angles(basis: [Vec3; 3]) -> Vec3 {
+    let [forward, left, up] = basis;
+    let xy_dist = (forward[0] * forward[0] + forward[1] * forward[1]).sqrt();
+    let pitch = (-forward[2]).atan2(xy_dist).to_degrees();
+
+    if xy_dist > 0.001 {
+        let yaw = forward[1].atan2(forward[0]).to_degrees();
+        let roll = left[2].atan2(up[2]).to_degrees();
+        Vec3::new(pitch, yaw, roll)
+    } else {
+        // Looking straight up or down, yaw and roll are the same thing here
+        let yaw = (-left[0]).atan2(left[1]).to_degrees();
+        Vec3::new(pitch, yaw, 0.0)
+    }
+}
+
+/// Applies a rotation given by its columns to a vector
+pub fn rotate(basis: &[Vec3; 3], v: Vec3) -> Vec3 {
+    basis[0] * v[0] + basis[1] * v[1] + basis[2] * v[2]
+}
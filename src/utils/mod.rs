@@ -3,3 +3,4 @@ pub mod vmt_helper;
 pub mod vtf_writer;
 pub mod nut_writer;
 pub mod text;
+pub mod rad_helper;
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Context;
+use log::{debug, info};
+use source_fs::{FileSystem, PackFile};
+
+use crate::math::Vec3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RadLight {
+    pub color: Vec3,
+    pub intensity: f32,
+}
+
+/// Emissive textures from `lights.rad` style files (material -> light)
+#[derive(Debug, Clone, Default)]
+pub struct RadLights {
+    entries: HashMap<String, RadLight>,
+}
+
+impl RadLights {
+    /// Parses `lights.rad` syntax: `[hdr:|ldr:|noHDR] material r g b i [r g b i]`.
+    /// When both LDR and HDR values are set, the HDR ones win
+    pub fn parse(data: &str) -> Self {
+        let mut rad = RadLights::default();
+
+        for line in data.lines() {
+            let line = line.split("//").next().unwrap_or("").trim();
+            let mut tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+
+            match tokens[0].to_lowercase().as_str() {
+                "ldr:" | "nohdr" | "forcetextureshadow" => continue, // Not used for HDR lighting
+                "hdr:" => { tokens.remove(0); }
+                _ => {}
+            }
+
+            let Some((material, values)) = tokens.split_first() else { continue };
+            let values: Vec<f32> = values.iter().filter_map(|v| v.parse().ok()).collect();
+            let light = match values.len() {
+                3 => (&values[0..3], 200.0),
+                4..=7 => (&values[0..3], values[3]),
+                8.. => (&values[4..7], values[7]),
+                _ => {
+                    debug!("lights.rad: skipping malformed line '{}'", line);
+                    continue;
+                }
+            };
+
+            let color = Vec3::new(light.0[0] / 255.0, light.0[1] / 255.0, light.0[2] / 255.0);
+            rad.entries.insert(normalize_material(material), RadLight { color, intensity: light.1 });
+        }
+
+        rad
+    }
+
+    /// Loads the game `lights.rad`, then the map specific `.rad` file on top of it
+    pub fn load<P: PackFile>(fs: &FileSystem<P>, map_rad: &Path) -> Self {
+        let mut rad = match fs.read("lights.rad", "game", true)
+            .map(|v| String::from_utf8_lossy(&v).to_string())
+            .context("\"lights.rad\" Not Found")
+        {
+            Ok(data) => RadLights::parse(&data),
+            Err(e) => {
+                debug!("{}", e);
+                RadLights::default()
+            }
+        };
+
+        if let Ok(data) = std::fs::read_to_string(map_rad) {
+            debug!("Loading map lights file: {:?}", map_rad);
+            rad.entries.extend(RadLights::parse(&data).entries);
+        }
+
+        info!("Loaded {} emissive textures from .rad files.", rad.entries.len());
+        rad
+    }
+
+    pub fn get(&self, material: &str) -> Option<&RadLight> {
+        self.entries.get(&normalize_material(material))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+fn normalize_material(material: &str) -> String {
+    let lower = material.trim().replace('\\', "/").to_lowercase();
+    let lower = lower.strip_prefix("materials/").unwrap_or(&lower);
+    lower.strip_suffix(".vmt").unwrap_or(lower).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rad() {
+        let rad = RadLights::parse("
+            // comment
+            lights/white001 255 255 255 200
+            LIGHTS/Panel 255 128 0 100 255 255 255 400 // hdr values
+            noHDR lights/ldr_only 1 2 3 4
+            hdr: lights/hdr_only 255 255 255 50
+            broken_line 1
+        ");
+
+        assert_eq!(rad.len(), 3);
+        assert_eq!(rad.get("lights/white001").unwrap().intensity, 200.0);
+        let panel = rad.get("lights/panel").unwrap();
+        assert_eq!(panel.intensity, 400.0);
+        assert_eq!(panel.color, Vec3::ONE);
+        assert!(rad.get("materials/Lights/HDR_only.vmt").is_some());
+        assert!(rad.get("lights/ldr_only").is_none());
+    }
+}
@@ -35,22 +35,69 @@ pub fn calc_face_normal(p: [Vec3; 3]) -> Vec3 {
 /// Applies an offset to all points in a plane row
 pub fn apply_offset_to_plane(plane_str: &str, offset: Vec3) -> String {
     if let Some(points) = parse_plane_points(plane_str) {
-        let p1 = points[0] - offset;
-        let p2 = points[1] - offset;
-        let p3 = points[2] - offset;
-
-        format!("({:.4} {:.4} {:.4}) ({:.4} {:.4} {:.4}) ({:.4} {:.4} {:.4})",
-            p1[0], p1[1], p1[2],
-            p2[0], p2[1], p2[2],
-            p3[0], p3[1], p3[2]
-        )
+        format_plane_points(points.map(|p| p - offset))
     } else {
         plane_str.to_string()
     }
 }
 
+/// Builds the VMF plane string "(x y z) (x y z) (x y z)"
+pub fn format_plane_points(points: [Vec3; 3]) -> String {
+    let [p1, p2, p3] = points;
+    format!("({:.4} {:.4} {:.4}) ({:.4} {:.4} {:.4}) ({:.4} {:.4} {:.4})",
+        p1[0], p1[1], p1[2],
+        p2[0], p2[1], p2[2],
+        p3[0], p3[1], p3[2]
+    )
+}
+
 pub fn sanitize_name(string: &str) -> String {
     string.chars()
         .filter(|&c| !matches!(c, '.' | '-' | ' '))
         .collect::<String>()
 }
+
+/// Case-insensitive glob match. Supports `*` (any sequence) and `?` (any single char)
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; // (star position in pattern, text position)
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("hall_lamp*", "hall_lamp_01"));
+        assert!(wildcard_match("HALL_*", "hall_lamp"));
+        assert!(wildcard_match("*lamp*", "big_lamp_02"));
+        assert!(wildcard_match("lamp_?", "lamp_3"));
+        assert!(wildcard_match("exact", "Exact"));
+        assert!(!wildcard_match("lamp_?", "lamp_12"));
+        assert!(!wildcard_match("hall_lamp*", "room_lamp"));
+        assert!(!wildcard_match("", "lamp"));
+    }
+}
@@ -1,7 +1,11 @@
+use crate::{DEFAULT_LIGHT_CHANNELS, TEXLIGHT_ID_BASE};
 use crate::math::Vec3;
-use crate::processing::geometry::get_entity_aabb;
-use crate::types::{BlockerDef, LightDef, LightType};
-use std::collections::HashMap;
+use crate::processing::light_rules::parse_channels;
+use crate::processing::geometry::{BrushFace, FaceRect, get_entity_aabb, get_entity_obb, solid_faces};
+use crate::rad_helper::RadLights;
+use crate::text::wildcard_match;
+use crate::types::{BlockerDef, BlockerType, LightDef, LightType};
+use std::collections::{HashMap, HashSet};
 use vmf_forge::prelude::*;
 
 const PBR_INTENSITY_MULT: f32 = 1.0;
@@ -10,14 +14,27 @@ const MAX_HDR_OVERBRIGHT: f32 = 16.0;
 // Brightness threshold where light is considered "zero" for range calculation.
 const LIGHT_CUTOFF_THRESHOLD: f32 = 0.2;
 
+// Fallback sizes for shaped point lights without 'pbr_radius' / 'pbr_length'
+const DEFAULT_SHAPE_RADIUS: f32 = 4.0;
+const DEFAULT_TUBE_LENGTH: f32 = 64.0;
+
+// Directional lights reach everything, so give them the max allowed range
+const SUN_RANGE: f32 = 65000.0;
+
 pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
     let mut lights = Vec::new();
-    let mut entity_map: HashMap<String, usize> = HashMap::new();
+    let mut named_entities: Vec<(&str, usize)> = Vec::new();
+    let mut blocker_groups: HashMap<String, Vec<usize>> = HashMap::new();
 
-    // Index entities for Blocker lookups
+    // Index entities for Blocker lookups (by name and by blocker group)
     for (idx, ent) in vmf.entities.iter().enumerate() {
         if let Some(name) = ent.targetname() {
-            entity_map.insert(name.to_string(), idx);
+            named_entities.push((name, idx));
+        }
+        if let Some(groups) = ent.get("pbr_blocker_group") {
+            for group in split_list(groups) {
+                blocker_groups.entry(group.to_lowercase()).or_default().push(idx);
+            }
         }
     }
 
@@ -25,7 +42,7 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
         let ent = &vmf.entities[i];
         let classname = ent.classname().unwrap_or("");
 
-        if classname == "light" || classname == "light_spot" || classname == "func_ggx_area" {
+        if classname == "light" || classname == "light_spot" || classname == "light_environment" || classname == "func_ggx_area" {
             // Skip disabled lights
             if classname != "func_ggx_area"
                 && ent.get("pbr_enabled").map(|v| v.as_str()).unwrap_or("0") == "0"
@@ -56,27 +73,35 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
             let fifty_percent_val = ent.get("_fifty_percent_distance").and_then(|s| s.parse::<f32>().ok()).filter(|&v| v > 0.1);
             let mut final_pos = origin_vec;
 
-            let mut shader_intensity;
+            let shader_intensity;
             let shader_k;
             let mut range;
             let light_type;
 
             if classname == "func_ggx_area" {
                 // Larger lights = Higher "Virtual Constant" = Softer falloff
-                let dir = angles_to_dir(ent.get("angles").unwrap_or(&"0 0 0".to_string()), None);
+                let mut dir = angles_to_dir(ent.get("angles").unwrap_or(&"0 0 0".to_string()), None);
                 let mut width = 0.0;
                 let mut height = 0.0;
 
-                if let Some(aabb) = get_entity_aabb(ent) {
+                // Reconstruct Shader Basis
+                let fwd = dir.normalize();
+                let up_base = if fwd[2].abs() > 0.99 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
+                let mut right_vec = fwd.cross(up_base).normalize();
+                let mut up_vec = right_vec.cross(fwd).normalize();
+
+                if let Some(rect) = find_emitting_face(ent, dir) {
+                    // Exact rectangle from the brush face, keeps any rotation and roll
+                    final_pos = rect.center;
+                    dir = rect.normal;
+                    right_vec = rect.right;
+                    up_vec = rect.up;
+                    width = rect.width.max(1.0);
+                    height = rect.height.max(1.0);
+                } else if let Some(aabb) = get_entity_aabb(ent) {
                     final_pos = aabb.center;
                     let extent = aabb.max - aabb.min; // Dimensions vector (dx, dy, dz)
 
-                    // Reconstruct Shader Basis
-                    let fwd = dir.normalize();
-                    let up_base = if fwd[2].abs() > 0.99 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
-                    let right_vec = fwd.cross(up_base).normalize();
-                    let up_vec = right_vec.cross(fwd).normalize();
-
                     // Project dimensions onto basis
                     let abs_right_vec = Vec3::new(right_vec[0].abs(), right_vec[1].abs(), right_vec[2].abs());
                     let abs_up_vec = Vec3::new(up_vec[0].abs(), up_vec[1].abs(), up_vec[2].abs());
@@ -87,38 +112,29 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
                     if height < 1.0 { height = 1.0; }
                 }
 
-                // Force standard quadratic falloff model for consistency with point lights.
-                // This prevents the excessive range and "infinite" falloff behavior of the original area light formula.
-                let c = 0.0;
-                let l = 0.0;
-                let q = 1.0;
-
-                let ratio = c + (100.0 * l) + (10000.0 * q);
-                let src_energy = if ratio > 0.001 { intensity * ratio } else { 0.0 };
-                let math_c = 1.0;
-
-                shader_intensity = src_energy / math_c;
-                shader_k = q / math_c;
-
-                // Normalize intensity to align with standard point light scoring.
-                // A factor of 0.25 balances the visual brightness and ensures the light's importance score
-                shader_intensity *= 0.25;
-
-                // Solver for Range
-                if shader_k > 1e-8 {
-                    let val = (shader_intensity / LIGHT_CUTOFF_THRESHOLD - 1.0) / shader_k;
-                    range = if val > 0.0 { val.sqrt() } else { 1000.0 };
-                } else {
-                    range = 10000.0;
-                }
+                (shader_intensity, shader_k, range) = area_light_falloff(intensity);
 
                 let bidirectional = ent.get("pbr_bidirectional").map(|s| s == "1").unwrap_or(false);
-                light_type = LightType::Rect {
-                    direction: dir,
-                    width,
-                    height,
-                    bidirectional,
-                };
+                let long_axis = if width >= height { right_vec } else { up_vec };
+                light_type = parse_light_shape(ent, dir, long_axis, (width, height))
+                    .unwrap_or(LightType::Rect {
+                        direction: dir,
+                        right: right_vec,
+                        width,
+                        height,
+                        bidirectional,
+                    });
+            } else if classname == "light_environment" {
+                // Sun has no falloff, the sky visibility is handled by scoring
+                let dir = angles_to_dir(
+                    ent.get("angles").unwrap_or(&"0 0 0".to_string()),
+                    ent.get("pitch").map(|s| s.as_str()),
+                );
+
+                shader_intensity = intensity;
+                shader_k = 0.0;
+                range = SUN_RANGE;
+                light_type = LightType::Directional { direction: dir };
             } else { // POINT & SPOT lights
                 let mut c = ent.get("_constant_attn").and_then(|s| s.parse::<f32>().ok()).unwrap_or(0.0);
                 let l = ent.get("_linear_attn").and_then(|s| s.parse::<f32>().ok()).unwrap_or(0.0);
@@ -197,7 +213,8 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
                         exponent: spot_expo,
                     };
                 } else {
-                    light_type = LightType::Point;
+                    let dir = angles_to_dir(ent.get("angles").unwrap_or(&"0 0 0".to_string()), None);
+                    light_type = parse_light_shape(ent, dir, dir, (0.0, 0.0)).unwrap_or(LightType::Point);
                 }
             }
 
@@ -206,31 +223,12 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
                 && r > 0.1 { range = r; }
             range = range.clamp(64.0, 65000.0);
 
-            // Blockers
-            let process_blocker = |key: &str| -> Option<BlockerDef> {
-                if let Some(name) = ent.get(key)
-                    && let Some(&idx) = entity_map.get(name)
-                        && let Some(aabb) = get_entity_aabb(&vmf.entities[idx]) {
-                            let mut flag = 1; // TODO: move it to fgd!!
-                            if let LightType::Rect { bidirectional: true, .. } = light_type {
-                                flag = 2; // temp workaround
-                            }
-                            return Some(BlockerDef {
-                                width: aabb.max[0] - aabb.min[0],
-                                height: aabb.max[1] - aabb.min[1],
-                                depth: aabb.max[2] - aabb.min[2],
-                                pos: Some(aabb.center),
-                                flag,
-                            });
-                        }
-                None
+            // Blockers. Untyped blockers of two-sided area lights are fizzlers (legacy behavior)
+            let default_blocker = match light_type {
+                LightType::Rect { bidirectional: true, .. } => BlockerType::Fizzler,
+                _ => BlockerType::Opaque,
             };
-
-            let blockers = [
-                process_blocker("pbr_blocker_name"),
-                process_blocker("pbr_blocker_name_2"),
-                // More can be added in the future .-.
-            ];
+            let blockers = collect_blockers(ent, i, &vmf.entities, &named_entities, &blocker_groups, default_blocker);
 
             // == PHASE 4: FINALIZEE
             let spawnflags = ent.get("spawnflags").and_then(|s| s.parse::<u32>().ok()).unwrap_or(0);
@@ -244,6 +242,8 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
                 target_name: targetname,
                 pbr_name,
                 is_named_light: ent.targetname().is_some(),
+                classname: classname.to_string(),
+                groups: ent.get("pbr_light_group").map(|g| split_list(g).map(String::from).collect()).unwrap_or_default(),
                 light_type,
                 pos: final_pos,
                 color,
@@ -253,6 +253,7 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
                 fifty_percent_distance: fifty_percent_val,
                 blockers,
                 initially_dark,
+                channels: ent.get("pbr_channels").and_then(|s| parse_channels(s)).unwrap_or(DEFAULT_LIGHT_CHANNELS),
             });
         }
     }
@@ -260,6 +261,67 @@ pub fn extract_lights(vmf: &VmfFile) -> anyhow::Result<Vec<LightDef>> {
     Ok(lights)
 }
 
+/// Turns every world / func_detail face with an emissive `.rad` material into a rect area light
+pub fn extract_texture_lights(vmf: &VmfFile, rad: &RadLights) -> Vec<LightDef> {
+    if rad.is_empty() {
+        return Vec::new();
+    }
+
+    let detail_solids = vmf.entities.iter()
+        .filter(|ent| ent.classname() == Some("func_detail"))
+        .filter_map(|ent| ent.solids.as_ref())
+        .flatten();
+
+    let mut lights = Vec::new();
+    for solid in vmf.world.solids.iter().chain(detail_solids) {
+        if !solid.sides.iter().any(|side| rad.get(&side.material).is_some()) {
+            continue;
+        }
+
+        for face in solid_faces(solid) {
+            let side = &solid.sides[face.side_idx];
+            let Some(rad_light) = rad.get(&side.material) else { continue };
+
+            let rect = face.bounding_rect();
+            if rect.width < 1.0 || rect.height < 1.0 || rad_light.intensity <= 0.0 {
+                continue;
+            }
+
+            let intensity = rad_light.intensity / MAX_HDR_OVERBRIGHT * PBR_INTENSITY_MULT;
+            let (intensity, attenuation_k, range) = area_light_falloff(intensity);
+
+            lights.push(LightDef {
+                id: TEXLIGHT_ID_BASE + side.id as u64,
+                target_name: String::new(),
+                pbr_name: format!("texlight_{}_{}", solid.id, side.id),
+                is_named_light: false,
+                // The material works as the group, so rules can pick texture lights by it
+                classname: "texlight".to_string(),
+                groups: vec![side.material.to_lowercase()],
+                light_type: LightType::Rect {
+                    direction: rect.normal,
+                    right: rect.right,
+                    width: rect.width,
+                    height: rect.height,
+                    bidirectional: false,
+                },
+                pos: rect.center,
+                color: rad_light.color,
+                intensity,
+                range: range.clamp(64.0, 65000.0),
+                attenuation_k,
+                fifty_percent_distance: None,
+                blockers: Vec::new(),
+                initially_dark: false,
+                channels: DEFAULT_LIGHT_CHANNELS,
+            });
+        }
+    }
+
+    log::info!("Created {} texture lights from emissive faces.", lights.len());
+    lights
+}
+
 /// Helper: Clean VMF in-place
 pub fn strip_pbr_entities(vmf: &mut VmfFile) {
     vmf.entities.retain(|ent| {
@@ -269,6 +331,155 @@ pub fn strip_pbr_entities(vmf: &mut VmfFile) {
 
 }
 
+/// Helper: Collect all blockers of a light.
+/// Matches every `pbr_blocker_name*` key (wildcards allowed) and shared `pbr_blocker_group` values
+fn collect_blockers(
+    light: &Entity,
+    light_idx: usize,
+    entities: &[Entity],
+    named_entities: &[(&str, usize)],
+    blocker_groups: &HashMap<String, Vec<usize>>,
+    default_kind: BlockerType,
+) -> Vec<BlockerDef> {
+    let mut matched: Vec<usize> = Vec::new();
+
+    for (key, pattern) in light.key_values.iter().filter(|(k, _)| k.starts_with("pbr_blocker_name")) {
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            continue;
+        }
+
+        let before = matched.len();
+        matched.extend(named_entities.iter()
+            .filter(|(name, _)| wildcard_match(pattern, name))
+            .map(|(_, idx)| *idx));
+
+        if matched.len() == before {
+            log::warn!("Light {} ({:?}): blocker '{}' from '{}' matches no entity.", light.id(), light.targetname(), pattern, key);
+        }
+    }
+
+    if let Some(groups) = light.get("pbr_blocker_group") {
+        for group in split_list(groups) {
+            matched.extend(blocker_groups.get(&group.to_lowercase()).into_iter().flatten());
+        }
+    }
+
+    let mut seen = HashSet::new();
+    matched.into_iter()
+        .filter(|&idx| idx != light_idx && seen.insert(idx))
+        .filter_map(|idx| {
+            let ent = &entities[idx];
+            let obb = get_entity_obb(ent)?;
+            let size = obb.size();
+
+            let kind = ent.get("pbr_blocker_type").and_then(|s| BlockerType::parse(s)).unwrap_or(default_kind);
+            let tint = ent.get("pbr_blocker_tint").map(|s| parse_color_intensity(s).0).unwrap_or(Vec3::ONE);
+
+            Some(BlockerDef {
+                id: ent.id(),
+                width: size[0],
+                height: size[1],
+                depth: size[2],
+                pos: obb.center,
+                axes: obb.axes,
+                kind,
+                tint,
+            })
+        })
+        .collect()
+}
+
+/// Helper: Split a list keyvalue ("a, b c") into its items
+fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    s.split([',', ';', ' ']).map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Helper: Find the emitting face of an area light brush and its exact rectangle.
+/// With 'angles' set, it's the face pointing closest to it. Otherwise the largest face, preferring down-facing ones
+fn find_emitting_face(ent: &Entity, angles_dir: Vec3) -> Option<FaceRect> {
+    let faces: Vec<BrushFace> = ent.solids.as_ref()?
+        .iter()
+        .flat_map(solid_faces)
+        .collect();
+
+    let has_angles = ent.get("angles").is_some();
+    let rank = |face: &BrushFace| -> (f32, f32) {
+        let area = face.area();
+        if has_angles {
+            // Round the alignment a bit, so equally aligned faces fall back to area
+            ((face.plane.normal.dot(angles_dir) * 100.0).round(), area)
+        } else {
+            (area.round(), -face.plane.normal[2])
+        }
+    };
+
+    faces.iter()
+        .max_by(|a, b| {
+            let (ra, rb) = (rank(a), rank(b));
+            ra.0.total_cmp(&rb.0).then(ra.1.total_cmp(&rb.1))
+        })
+        .map(BrushFace::bounding_rect)
+        .filter(|rect| rect.width > 0.0 && rect.height > 0.0)
+}
+
+/// Helper: Intensity, attenuation k and range of an area light (func_ggx_area or texture light)
+fn area_light_falloff(intensity: f32) -> (f32, f32, f32) {
+    // Force standard quadratic falloff model for consistency with point lights.
+    // This prevents the excessive range and "infinite" falloff behavior of the original area light formula.
+    let c = 0.0;
+    let l = 0.0;
+    let q = 1.0;
+
+    let ratio = c + (100.0 * l) + (10000.0 * q);
+    let src_energy = if ratio > 0.001 { intensity * ratio } else { 0.0 };
+    let math_c = 1.0;
+
+    let mut shader_intensity = src_energy / math_c;
+    let shader_k = q / math_c;
+
+    // Normalize intensity to align with standard point light scoring.
+    // A factor of 0.25 balances the visual brightness and ensures the light's importance score
+    shader_intensity *= 0.25;
+
+    // Solver for Range
+    let range = if shader_k > 1e-8 {
+        let val = (shader_intensity / LIGHT_CUTOFF_THRESHOLD - 1.0) / shader_k;
+        if val > 0.0 { val.sqrt() } else { 1000.0 }
+    } else {
+        10000.0
+    };
+
+    (shader_intensity, shader_k, range)
+}
+
+/// Helper: Read the optional `pbr_shape` key (with `pbr_radius` and `pbr_length`).
+/// `size` is the (width, height) of the source brush, used when no explicit size is set.
+/// Returns None for the default shape of the entity.
+fn parse_light_shape(ent: &Entity, normal: Vec3, tube_axis: Vec3, size: (f32, f32)) -> Option<LightType> {
+    let shape = ent.get("pbr_shape")?.trim().to_lowercase();
+    let radius_key = ent.get("pbr_radius").and_then(|s| s.parse::<f32>().ok()).filter(|&r| r > 0.0);
+    let length_key = ent.get("pbr_length").and_then(|s| s.parse::<f32>().ok()).filter(|&l| l > 0.0);
+
+    let min_size = size.0.min(size.1);
+    let radius = radius_key.unwrap_or(if min_size > 0.0 { min_size * 0.5 } else { DEFAULT_SHAPE_RADIUS });
+
+    match shape.as_str() {
+        "" | "default" | "point" | "rect" => None,
+        "sphere" => Some(LightType::Sphere { radius }),
+        "tube" | "capsule" => {
+            let max_size = size.0.max(size.1);
+            let length = length_key.unwrap_or(if max_size > 0.0 { max_size } else { DEFAULT_TUBE_LENGTH });
+            Some(LightType::Tube { direction: tube_axis.normalize(), length, radius })
+        }
+        "disk" | "disc" => Some(LightType::Disk { direction: normal.normalize(), radius }),
+        other => {
+            log::warn!("Light {} ({:?}): unknown pbr_shape '{}'. Using default shape.", ent.id(), ent.targetname(), other);
+            None
+        }
+    }
+}
+
 /// Helper: Parse Source "_light" string
 fn parse_color_intensity(s: &str) -> (Vec3, f32) {
     let parts: Vec<f32> = s.split_whitespace().filter_map(|v| v.parse().ok()).collect();